@@ -0,0 +1,172 @@
+use std::{collections::HashMap, hint::black_box};
+
+use chimera::{find, reference::{EfficientGuides, FinalGuides, Ref}, synthetic::Bases};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use itertools::Itertools;
+
+const INPUT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testing/second-run/CAG-B_S20_L001_R1_001/1000/input.fastq");
+const ERROR_RATE: f32 = 0.25;
+// the default error rate is too high to index 20 nt spacers, so every guide is checked; this one indexes them
+const INDEXED_ERROR_RATE: f32 = 0.1;
+
+// synthetic libraries, and how many reads of each are classified
+const LIBRARY_SIZES: [usize; 3] = [100, 10_000, 100_000];
+const SYNTHETIC_READS: usize = 200;
+
+fn reads() -> Vec<Vec<u8>> {
+    bio::io::fastq::Reader::from_file(INPUT).expect("Bad input!")
+        .records()
+        .map(|record| record.expect("Bad record!").seq().to_vec())
+        .collect_vec()
+}
+
+/// The reference of the bundled reads isn't bundled with them, so one is made from the reads:
+/// each spacer paired with the extension and nicking sequence it's most often read with
+fn bundled_reference(reads: &[Vec<u8>]) -> Ref {
+    // an empty reference is enough to break reads into their regions
+    let structure = Ref::from_tsv("");
+
+    let mut pairings: HashMap<[&[u8]; 3], usize> = HashMap::new();
+    for read in reads {
+        if let Some(find::Regions { spacer, extension, nicking: Some(nicking), .. }) =
+            find::break_into_regions(read, &structure, ERROR_RATE) {
+            *pairings.entry([spacer, extension, nicking]).or_default() += 1;
+        }
+    }
+
+    let seq = |seq: &[u8]| String::from_utf8(seq.to_vec()).expect("Bad sequence!");
+    let tsv: String = pairings.into_iter()
+        .filter(|([spacer, _, _], _)| !spacer.is_empty())
+        // the most common pairing of each spacer first, then the rest of it dropped
+        .sorted_by_key(|(seqs, count)| (seqs[0], std::cmp::Reverse(*count), *seqs))
+        .dedup_by(|(a, _), (b, _)| a[0] == b[0])
+        .enumerate()
+        .map(|(i, ([spacer, extension, nicking], _))|
+            format!("g{}\t{}\t{}\t{}\n", i, seq(spacer), seq(extension), seq(nicking)))
+        .collect();
+
+    Ref::from_tsv(&tsv)
+}
+
+/// A library of random guides, and reads of them laid out like the bundled ones:
+/// mostly valid, with some chimeric and some with a substitution in the spacer
+fn synthetic(guides: usize) -> (Ref, Vec<Vec<u8>>) {
+    let mut bases = Bases(0x9e3779b97f4a7c15 ^ guides as u64);
+    let library = (0..guides)
+        .map(|_| {
+            let extension_len = 30 + bases.below(20);
+            [bases.seq(20), bases.seq(extension_len), bases.seq(20)]
+        })
+        .collect_vec();
+
+    let tsv: String = library.iter().enumerate()
+        .map(|(i, seqs)| format!("g{}\t{}\n", i, seqs.iter().map(|seq| String::from_utf8_lossy(seq)).join("\t")))
+        .collect();
+    let reference = Ref::from_tsv(&tsv);
+
+    let (cys4, scaffold) = (&reference.cys4.seq, &reference.scaffold.seq);
+    let reads = (0..SYNTHETIC_READS)
+        .map(|i| {
+            let guide = bases.below(guides);
+            // every fifth read has another guide's extension and nicking sgRNA
+            let partner = if i % 5 == 0 { bases.below(guides) } else { guide };
+            let ([spacer, _, _], [_, extension, nicking]) = (&library[guide], &library[partner]);
+
+            let primer = bases.seq(10);
+            let mut read = [&primer[..], cys4, spacer, scaffold, extension, cys4, nicking, scaffold, &bases.seq(10)].concat();
+            if i % 7 == 0 {
+                let pos = primer.len() + cys4.len() + bases.below(spacer.len());
+                read[pos] = if read[pos] == b'A' { b'C' } else { b'A' };
+            }
+            read
+        })
+        .collect_vec();
+
+    (reference, reads)
+}
+
+/// Finding the regions and classifying every bundled read, one at a time
+fn bundled(c: &mut Criterion) {
+    let reads = reads();
+    let reference = bundled_reference(&reads);
+    let efficient_guides = EfficientGuides::new(&reference.guides, ERROR_RATE);
+    let final_guides = FinalGuides::new(&reference.guides, ERROR_RATE, false);
+    let regions = reads.iter()
+        .map(|read| find::break_into_regions(read, &reference, ERROR_RATE))
+        .collect_vec();
+
+    let mut group = c.benchmark_group("bundled");
+    group.throughput(Throughput::Elements(reads.len() as u64));
+    group.bench_function("regions", |b| b.iter(|| {
+        for read in &reads {
+            black_box(find::break_into_regions(read, &reference, ERROR_RATE));
+        }
+    }));
+    group.bench_function("quick", |b| b.iter(|| {
+        for read_regions in &regions {
+            black_box(find::structure_classify_quickly(*read_regions, &reference, &final_guides, ERROR_RATE));
+        }
+    }));
+    group.bench_function("careful", |b| b.iter(|| {
+        for read_regions in &regions {
+            black_box(find::structure_classify_carefully(*read_regions, &reference, &efficient_guides, ERROR_RATE));
+        }
+    }));
+    group.finish();
+}
+
+/// Building the guides of synthetic libraries of each size, and classifying reads against them
+fn synthetic_libraries(c: &mut Criterion) {
+    let libraries = LIBRARY_SIZES.map(synthetic);
+
+    // the largest libraries take seconds an iteration
+    let mut building = c.benchmark_group("reference");
+    building.sample_size(10);
+    for (size, (reference, _)) in LIBRARY_SIZES.iter().zip(&libraries) {
+        building.throughput(Throughput::Elements(*size as u64));
+        for (suffix, error_rate) in [("", ERROR_RATE), ("_indexed", INDEXED_ERROR_RATE)] {
+            building.bench_with_input(BenchmarkId::new(format!("final_guides{}", suffix), size), reference, |b, reference| {
+                b.iter(|| FinalGuides::new(&reference.guides, error_rate, false))
+            });
+            building.bench_with_input(BenchmarkId::new(format!("efficient_guides{}", suffix), size), reference, |b, reference| {
+                b.iter(|| EfficientGuides::new(&reference.guides, error_rate))
+            });
+        }
+    }
+    building.finish();
+
+    let mut classifying = c.benchmark_group("synthetic");
+    classifying.sample_size(10);
+    classifying.throughput(Throughput::Elements(SYNTHETIC_READS as u64));
+    // the regions are found the same way whatever the library
+    let (reference, reads) = &libraries[0];
+    classifying.bench_function("regions", |b| b.iter(|| {
+        for read in reads {
+            black_box(find::break_into_regions(read, reference, ERROR_RATE));
+        }
+    }));
+    for (size, (reference, reads)) in LIBRARY_SIZES.iter().zip(&libraries) {
+        for (suffix, error_rate) in [("", ERROR_RATE), ("_indexed", INDEXED_ERROR_RATE)] {
+            let efficient_guides = EfficientGuides::new(&reference.guides, error_rate);
+            let final_guides = FinalGuides::new(&reference.guides, error_rate, false);
+            let regions = reads.iter()
+                .map(|read| find::break_into_regions(read, reference, error_rate))
+                .collect_vec();
+
+            classifying.bench_with_input(BenchmarkId::new(format!("quick{}", suffix), size), &regions, |b, regions| b.iter(|| {
+                for read_regions in regions {
+                    black_box(find::structure_classify_quickly(*read_regions, reference, &final_guides, error_rate));
+                }
+            }));
+            classifying.bench_with_input(BenchmarkId::new(format!("careful{}", suffix), size), &regions, |b, regions| b.iter(|| {
+                for read_regions in regions {
+                    black_box(find::structure_classify_carefully(*read_regions, reference, &efficient_guides, error_rate));
+                }
+            }));
+        }
+    }
+    classifying.finish();
+}
+
+criterion_group!(benches, bundled, synthetic_libraries);
+criterion_main!(benches);
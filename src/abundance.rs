@@ -0,0 +1,47 @@
+use std::io::Write;
+
+use crate::reference::Ref;
+
+/// Whether a guide has far more or fewer valid reads than it was designed to
+fn outlier(ratio: f64, fold: f64) -> &'static str {
+    if ratio >= fold {
+        "enriched"
+    } else if ratio <= 1.0 / fold {
+        "depleted"
+    } else {
+        ""
+    }
+}
+
+/// The abundance each guide was designed to have: the reference's expected column, or 1 for every guide
+/// without one. Guides can't be weighted against each other when only some of them have one
+pub fn weights(reference: &Ref) -> Vec<f64> {
+    let weighted = reference.guides.iter().filter(|guide| guide.expected.is_some()).count();
+    if weighted != 0 && weighted != reference.guides.len() {
+        panic!("Only {} of {} guides have an expected abundance! Give it for every guide, or none", weighted, reference.guides.len());
+    }
+
+    reference.guides.iter()
+        .map(|guide| guide.expected.unwrap_or(1.0))
+        .collect()
+}
+
+/// Writes the expected and observed fraction of valid reads for each guide, flagging those more than
+/// `fold` times off
+pub fn write_abundance<T: Write>(output: &mut T, reference: &Ref, valid_counts: &[(String, u32)], fold: f64) {
+    writeln!(output, "guide\texpected\texpected_fraction\tobserved\tobserved_fraction\tlog2_ratio\toutlier")
+        .expect("Couldn't write header line to abundance!");
+
+    let weights = weights(reference);
+    let total_weight = weights.iter().sum::<f64>();
+    let total_valid = valid_counts.iter().map(|(_, count)| *count as f64).sum::<f64>();
+
+    for ((guide, count), weight) in valid_counts.iter().zip(weights) {
+        let expected = weight / total_weight;
+        let observed = *count as f64 / total_valid;
+        let ratio = observed / expected;
+        writeln!(output, "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            guide, weight, expected, count, observed, ratio.log2(), outlier(ratio, fold))
+            .expect("Couldn't write line to abundance!");
+    }
+}
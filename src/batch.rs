@@ -0,0 +1,208 @@
+use std::{fs, io::Write, path::Path, time::Instant};
+
+use itertools::Itertools;
+
+use crate::{cache, find, input, merge, multiqc, output, summary, Args, Classifier, OutStats, OutputPaths, SampleOutputs};
+
+#[derive(Debug, clap::Args)]
+#[command(
+    mut_arg("reference_tsv", |arg| arg.required(false)),
+    mut_arg("chimera_fastq", |arg| arg.required(false)),
+    mut_arg("valid_fastq", |arg| arg.required(false)),
+    mut_arg("output_tsv", |arg| arg.required(false)),
+)]
+pub struct BatchArgs {
+    /// Tab-separated sheet of sample name, input FASTQs (comma-separated), and optionally a reference,
+    /// which otherwise defaults to --reference-tsv.
+    #[arg(long)]
+    samples: String,
+
+    /// Directory holding one directory of outputs for each sample, and the combined stats, count matrix and chimera rates.
+    /// Output paths given as options are taken as file names within each sample's directory.
+    #[arg(long, default_value_t = String::from("chimera-batch"))]
+    output_dir: String,
+
+    #[command(flatten)]
+    args: Args,
+}
+
+/// What's kept of each sample once it's been classified
+struct BatchSample {
+    sample: String,
+    reference: String,
+    stats: OutStats,
+    valid_counts: Vec<(String, u32)>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct SampleRecord {
+    sample: String,
+    fastqs: String,
+    #[serde(default)]
+    reference: Option<String>,
+}
+
+/// Reads the sample sheet, giving each sample the reference it should use
+fn parse_samples(samples: &str, default_reference: Option<&str>) -> Vec<(String, Vec<String>, String)> {
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .has_headers(false)
+        .flexible(true)
+        .from_path(Path::new(samples))
+        .expect("Bad sample sheet!");
+
+    let samples = reader.deserialize()
+        .map(|result| {
+            let record: SampleRecord = result.expect("Bad sample sheet row!");
+            let reference = record.reference
+                .filter(|reference| !reference.is_empty())
+                .or(default_reference.map(String::from))
+                .unwrap_or_else(|| panic!("Sample {} has no reference, and no --reference-tsv was given!", record.sample));
+            let fastqs = record.fastqs.split(',').map(|fastq| fastq.trim().to_string()).collect_vec();
+
+            (record.sample, fastqs, reference)
+        })
+        .collect_vec();
+
+    // each sample's outputs are named for it, so a second sample of a name would overwrite the first's
+    if let Some(sample) = samples.iter().map(|(sample, _, _)| sample).duplicates().next() {
+        panic!("Sample {} is in the sample sheet twice!", sample);
+    }
+    samples
+}
+
+/// Outputs of one sample, each named as in the arguments but placed in the sample's directory
+fn sample_paths(args: &Args, dir: &Path) -> OutputPaths {
+    let path = |path: &str| {
+        let name = Path::new(path).file_name().expect("Bad output path!");
+        dir.join(name).to_str().expect("Bad output path!").to_string()
+    };
+
+    OutputPaths {
+        output_tsv: path(args.output_tsv.as_deref().unwrap_or("output.tsv")),
+        valid_fastq: path(args.valid_fastq.as_deref().unwrap_or("valid.fastq")),
+        chimera_fastq: path(args.chimera_fastq.as_deref().unwrap_or("chimera.fastq")),
+        guide_counts: args.guide_counts.as_deref().map(path),
+        abundance: args.abundance.as_deref().map(path),
+        chimera_counts: args.chimera_counts.as_deref().map(path),
+        error_profile: args.error_profile.as_deref().map(path),
+        guide_error_profile: args.guide_error_profile.as_deref().map(path),
+        partners: args.partners.as_deref().map(path),
+        pool_stats: args.pool_stats.as_deref().map(path),
+        html_report: args.html_report.as_deref().map(path),
+        compare_modes: args.compare_modes.as_deref().map(path),
+    }
+}
+
+/// Writes one row of stats per sample. Stats only some samples have, like those of pools, are 0 for the rest
+fn write_stats<T: Write>(output: &mut T, samples: &[BatchSample], dedup: bool) {
+    let names = samples.iter()
+        .flat_map(|sample| sample.stats.stats(dedup).into_iter().map(|(name, _)| name))
+        .unique()
+        .collect_vec();
+    writeln!(output, "sample\treference\t{}", names.join("\t"))
+        .expect("Couldn't write header line to batch stats!");
+
+    for BatchSample { sample, reference, stats, .. } in samples {
+        let stats = stats.stats(dedup);
+        let values = names.iter()
+            .map(|name| stats.iter().find(|(stat, _)| stat == name).map_or(0, |(_, value)| *value))
+            .join("\t");
+        writeln!(output, "{}\t{}\t{}", sample, reference, values)
+            .expect("Couldn't write line to batch stats!");
+    }
+}
+
+pub fn batch_main(batch_args: &BatchArgs) {
+    let args = &batch_args.args;
+    if args.sample_sheet.is_some() {
+        panic!("Can't demultiplex in batch mode! Give each sample its own FASTQs instead");
+    }
+
+    let started = Instant::now();
+    let mut summary = summary::Summary::new(args);
+
+    let samples = parse_samples(&batch_args.samples, args.reference_tsv.as_deref());
+    let output_dir = Path::new(&batch_args.output_dir);
+
+    // build each reference once, and run all of its samples against it
+    let mut stats = Vec::new();
+    for (reference, reference_samples) in &samples.iter()
+        .enumerate()
+        .sorted_by_key(|(i, (_, _, reference))| (reference, *i))
+        .chunk_by(|(_, (_, _, reference))| reference) {
+        let building = Instant::now();
+        let classifier = Classifier::new(args, reference);
+        summary.add_reference(reference, classifier.reference.guides.len(), building.elapsed());
+        // each reference classifies reads differently, so has its own cache
+        let cache = args.cache.map(cache::Cache::new);
+
+        for (i, (sample, fastqs, _)) in reference_samples {
+            let dir = output_dir.join(sample);
+            fs::create_dir_all(&dir).expect("Couldn't create sample output directory!");
+
+            let mut outputs = [(Some(&sample[..]), SampleOutputs::new(
+                sample_paths(args, &dir), !classifier.reference.pools.is_empty(), classifier.mode == find::Mode::Adaptive
+            ))];
+            let classifying = Instant::now();
+            for fastq in fastqs {
+                classifier.run(input::path_reader(fastq), None, &mut outputs, cache.as_ref());
+            }
+            summary.add_classification(classifying.elapsed());
+
+            let [(_, outputs)] = outputs;
+            let out_stats = outputs.finish(Some(sample), &classifier.reference, args);
+            out_stats.report(Some(sample), args);
+            stats.push((i, BatchSample {
+                sample: sample.clone(),
+                reference: reference.clone(),
+                valid_counts: out_stats.valid_counts(&classifier.reference),
+                stats: out_stats,
+            }));
+        }
+
+        if let Some(cache) = &cache {
+            summary.add_cache(cache.summary());
+        }
+    }
+
+    // report the samples in the order they were given
+    let stats = stats.into_iter()
+        .sorted_by_key(|(i, _)| *i)
+        .map(|(_, stats)| stats)
+        .collect_vec();
+    let path = |default: &str, path: &Option<String>| {
+        let name = Path::new(path.as_deref().unwrap_or(default)).file_name().expect("Bad output path!");
+        output_dir.join(name).to_str().expect("Bad output path!").to_string()
+    };
+    write_stats(&mut output::path_writer(&path("stats.tsv", &None)), &stats, args.dedup);
+
+    let mut matrix = merge::CountMatrix::new();
+    for BatchSample { sample, valid_counts, .. } in &stats {
+        matrix.add_sample(sample, valid_counts.iter().cloned());
+    }
+    matrix.write(&mut output::path_writer(&path("counts.tsv", &args.count_matrix)));
+
+    let rates = stats.iter()
+        .map(|BatchSample { sample, stats, .. }| stats.chimera_rate(sample))
+        .collect_vec();
+    merge::write_chimera_rates(&mut output::path_writer(&path("chimera_rates.tsv", &args.chimera_rates)), &rates);
+
+    if args.multiqc.is_some() {
+        let multiqc_samples = stats.iter()
+            .map(|BatchSample { sample, stats, valid_counts, .. }| multiqc::MultiqcSample { 
+                name: sample.clone(),
+                stats: stats.stats(args.dedup),
+                classes: stats.classes(),
+                valid_counts: valid_counts.clone(),
+            })
+            .collect_vec();
+        multiqc::write_multiqc(&path("multiqc", &args.multiqc), &multiqc_samples);
+    }
+    if args.summary.is_some() {
+        for BatchSample { sample, stats, valid_counts, .. } in &stats {
+            summary.add_sample(Some(sample), &stats.stats(args.dedup), &stats.classes(), valid_counts);
+        }
+        summary.write(&mut output::path_writer(&path("summary.json", &args.summary)), started.elapsed());
+    }
+}
@@ -0,0 +1,86 @@
+use std::{collections::HashMap, hash::{BuildHasher, RandomState}, sync::{atomic::{AtomicUsize, Ordering}, Mutex}};
+
+use serde::Serialize;
+
+use crate::find::Regions;
+
+// separate locks, so threads rarely wait on each other
+const SHARDS: usize = 64;
+
+/// Classifications of the regions of reads seen so far, so that repeats of them needn't be classified again.
+/// Once full, it keeps what it has; in amplicon data the common sequences turn up early
+pub struct Cache<T> {
+    shards: Vec<Mutex<HashMap<Vec<u8>, T>>>,
+    hasher: RandomState,
+    shard_capacity: usize,
+    lookups: AtomicUsize,
+    hits: AtomicUsize,
+}
+
+/// How well the cache did, for the summary
+#[derive(Serialize, Default)]
+pub struct CacheSummary {
+    pub capacity: usize,
+    pub entries: usize,
+    pub lookups: usize,
+    pub hits: usize,
+    pub hit_rate: f64,
+}
+
+impl<T: Clone> Cache<T> {
+    pub fn new(capacity: usize) -> Self {
+        Cache {
+            shards: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+            hasher: RandomState::new(),
+            shard_capacity: capacity.div_ceil(SHARDS),
+            lookups: AtomicUsize::new(0),
+            hits: AtomicUsize::new(0),
+        }
+    }
+
+    /// Either finds the classification of the same regions, or classifies them and remembers it
+    pub fn classify(&self, regions: &Regions, classify: impl FnOnce() -> T) -> T {
+        // the tail isn't classified, so isn't part of the key
+        let mut key = [regions.spacer, &b"|"[..], regions.extension].concat();
+        if let Some(nicking) = regions.nicking {
+            key.push(b'|');
+            key.extend(nicking);
+        }
+        let shard = &self.shards[self.hasher.hash_one(&key) as usize % SHARDS];
+
+        self.lookups.fetch_add(1, Ordering::Relaxed);
+        if let Some(result) = shard.lock().expect("Poisoned cache!").get(&key) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return result.clone();
+        }
+
+        let result = classify();
+        let mut shard = shard.lock().expect("Poisoned cache!");
+        if shard.len() < self.shard_capacity {
+            shard.insert(key, result.clone());
+        }
+        result
+    }
+
+    pub fn summary(&self) -> CacheSummary {
+        let (lookups, hits) = (self.lookups.load(Ordering::Relaxed), self.hits.load(Ordering::Relaxed));
+        CacheSummary {
+            capacity: self.shard_capacity * SHARDS,
+            entries: self.shards.iter().map(|shard| shard.lock().expect("Poisoned cache!").len()).sum(),
+            lookups,
+            hits,
+            hit_rate: hits as f64 / lookups as f64,
+        }
+    }
+}
+
+impl CacheSummary {
+    /// Adds up the caches of several references
+    pub fn add(&mut self, other: CacheSummary) {
+        self.capacity += other.capacity;
+        self.entries += other.entries;
+        self.lookups += other.lookups;
+        self.hits += other.hits;
+        self.hit_rate = self.hits as f64 / self.lookups as f64;
+    }
+}
@@ -0,0 +1,63 @@
+use std::{collections::HashMap, fs, io::Write, path::Path};
+
+use crate::{find::{RefResult, StructureResult, CLASSES}, output};
+
+/// The guide and errors of a classification, where it has them
+fn guide_errors(result: &StructureResult) -> [String; 3] {
+    match result {
+        StructureResult::WellStructured(r) | StructureResult::MotifTruncated(r) => match r {
+            RefResult::Valid(name, mismatch) | RefResult::RecombinedRtt(name, mismatch) =>
+                [name.to_string(), mismatch.dist.to_string(), mismatch.len.to_string()],
+            RefResult::Chimera | RefResult::Ambiguous => Default::default(),
+        },
+        StructureResult::BadlyStructured => Default::default(),
+    }
+}
+
+/// Quick and careful classifications of the same reads, side by side: the reads they put in different
+/// classes or assign different guides, as they go, and how often each pair of classes came up
+pub struct ModeComparison {
+    dir: String,
+    disagreements: Box<dyn Write>,
+    confusion: HashMap<(&'static str, &'static str), u32>,
+}
+
+impl ModeComparison {
+    pub fn new(dir: &str) -> Self {
+        fs::create_dir_all(dir).expect("Couldn't create mode comparison directory!");
+        let path = Path::new(dir).join("disagreements.tsv");
+        let mut disagreements = output::path_writer(path.to_str().expect("Bad output path!"));
+        writeln!(disagreements, "read\tquick\tquick_guide\tquick_dist\tquick_len\tcareful\tcareful_guide\tcareful_dist\tcareful_len")
+            .expect("Couldn't write header line to mode disagreements!");
+
+        ModeComparison { dir: dir.to_string(), disagreements, confusion: HashMap::new() }
+    }
+
+    pub fn add(&mut self, id: &str, quick: &StructureResult, careful: &StructureResult) {
+        let classes = (quick.class(), careful.class());
+        *self.confusion.entry(classes).or_default() += 1;
+
+        if classes.0 != classes.1 || quick.guide() != careful.guide() {
+            let [quick_guide, quick_dist, quick_len] = guide_errors(quick);
+            let [careful_guide, careful_dist, careful_len] = guide_errors(careful);
+            writeln!(self.disagreements, "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                id, classes.0, quick_guide, quick_dist, quick_len, classes.1, careful_guide, careful_dist, careful_len)
+                .expect("Couldn't write line to mode disagreements!");
+        }
+    }
+
+    /// Writes the confusion table, with a row for each quick class and a column for each careful class
+    pub fn finish(self) {
+        let path = Path::new(&self.dir).join("confusion.tsv");
+        let mut output = output::path_writer(path.to_str().expect("Bad output path!"));
+
+        let classes = CLASSES.map(|(class, _)| class);
+        writeln!(output, "quick\\careful\t{}", classes.join("\t"))
+            .expect("Couldn't write header line to mode confusion!");
+        for quick in classes {
+            let counts = classes.map(|careful| self.confusion.get(&(quick, careful)).unwrap_or(&0).to_string());
+            writeln!(output, "{}\t{}", quick, counts.join("\t"))
+                .expect("Couldn't write line to mode confusion!");
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use itertools::Itertools;
+use serde::Serialize;
+
+/// Valid reads per guide, summarised over the library
+#[derive(Serialize)]
+pub struct Distribution {
+    pub min: f64,
+    pub p10: f64,
+    pub p25: f64,
+    pub median: f64,
+    pub p75: f64,
+    pub p90: f64,
+    pub max: f64,
+    pub mean: f64,
+}
+
+/// How evenly the valid reads are spread over the guides of a library
+#[derive(Serialize)]
+pub struct Coverage {
+    pub guides: usize,
+    // guides without a single valid read
+    pub dropouts: usize,
+    // guides with between a tenth and ten times the median reads
+    pub within_10x_median: f64,
+    pub gini: f64,
+    // 90th percentile over 10th percentile, which is infinite (null) when the 10th percentile is a dropout
+    pub skew_ratio: f64,
+    pub reads_per_guide: Distribution,
+}
+
+/// Linearly interpolates between the closest ranks of sorted values
+fn quantile(sorted: &[f64], q: f64) -> f64 {
+    if sorted.is_empty() {
+        return f64::NAN;
+    }
+
+    let rank = q * (sorted.len() - 1) as f64;
+    let (below, above) = (rank.floor() as usize, rank.ceil() as usize);
+    sorted[below] + (sorted[above] - sorted[below]) * (rank - below as f64)
+}
+
+/// Half the mean absolute difference between guides, relative to the mean. 0 is perfectly even
+fn gini(sorted: &[f64]) -> f64 {
+    let n = sorted.len() as f64;
+    let total = sorted.iter().sum::<f64>();
+
+    let weighted = sorted.iter().enumerate()
+        .map(|(i, count)| (i + 1) as f64 * count)
+        .sum::<f64>();
+    2.0 * weighted / (n * total) - (n + 1.0) / n
+}
+
+impl Coverage {
+    pub fn new(counts: &[u32]) -> Self {
+        let sorted = counts.iter().map(|count| *count as f64).sorted_by(f64::total_cmp).collect_vec();
+        let median = quantile(&sorted, 0.5);
+        let (p10, p90) = (quantile(&sorted, 0.1), quantile(&sorted, 0.9));
+
+        Coverage {
+            guides: counts.len(),
+            dropouts: counts.iter().filter(|count| **count == 0).count(),
+            within_10x_median: sorted.iter()
+                .filter(|count| **count >= median / 10.0 && **count <= median * 10.0)
+                .count() as f64 / sorted.len() as f64,
+            gini: gini(&sorted),
+            skew_ratio: p90 / p10,
+            reads_per_guide: Distribution {
+                min: sorted.first().copied().unwrap_or(f64::NAN),
+                p10,
+                p25: quantile(&sorted, 0.25),
+                median,
+                p75: quantile(&sorted, 0.75),
+                p90,
+                max: sorted.last().copied().unwrap_or(f64::NAN),
+                mean: sorted.iter().sum::<f64>() / sorted.len() as f64,
+            },
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use std::path::Path;
+
+use itertools::Itertools;
+
+use crate::reference::bases_match;
+
+/// Name given to reads whose index matches no sample
+pub const UNDETERMINED: &str = "undetermined";
+
+#[derive(Debug, serde::Deserialize)]
+struct SampleRecord {
+    sample: String,
+    index: String,
+}
+
+/// Index sequences of each sample, as found at the end of read headers (`1:N:0:AACTTGCG`).
+/// Dual indexes are written as one, like AACTTGCG+GGTTAACC
+pub struct SampleSheet {
+    pub samples: Vec<String>,
+    indexes: Vec<(usize, Vec<u8>)>,
+    mismatches: usize,
+}
+
+impl SampleSheet {
+    pub fn new(sample_sheet: &str, mismatches: usize) -> SampleSheet {
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(b'\t')
+            .has_headers(false)
+            .from_path(Path::new(sample_sheet))
+            .expect("Bad sample sheet!");
+
+        let mut samples: Vec<String> = Vec::new();
+        let mut indexes = Vec::new();
+        for result in reader.deserialize() {
+            let record: SampleRecord = result.expect("Bad sample sheet row!");
+            if record.sample == UNDETERMINED {
+                panic!("Sample can't be called {}!", UNDETERMINED);
+            }
+
+            // a sample may have several indexes
+            let sample = match samples.iter().position(|sample| *sample == record.sample) {
+                Some(sample) => sample,
+                None => { samples.push(record.sample); samples.len() - 1 },
+            };
+            indexes.push((sample, record.index.to_ascii_uppercase().into_bytes()));
+        }
+
+        SampleSheet { samples, indexes, mismatches }
+    }
+
+    /// Finds the sample of a read from the index in its description,
+    /// or None if no sample is within the mismatch tolerance, or several are equally close
+    pub fn assign(&self, desc: Option<&str>) -> Option<usize> {
+        let index = desc?.rsplit(':').next()?.as_bytes();
+
+        let distances = self.indexes.iter()
+            .filter(|(_, sample_index)| sample_index.len() == index.len())
+            .map(|(sample, sample_index)| {
+                let distance = sample_index.iter().zip(index)
+                    .filter(|(s, r)| !bases_match(**s, **r))
+                    .count();
+                (*sample, distance)
+            })
+            .filter(|(_, distance)| *distance <= self.mismatches)
+            .collect_vec();
+
+        let best = distances.iter().map(|(_, distance)| *distance).min()?;
+        match &distances.iter()
+            .filter(|(_, distance)| *distance == best)
+            .map(|(sample, _)| *sample)
+            .unique()
+            .collect_vec()[..] {
+            [sample] => Some(*sample),
+            _ => None,
+        }
+    }
+}
+
+/// Puts the sample name into an output path, before its extensions: out.tsv becomes out.S1.tsv
+pub fn sample_path(path: &str, sample: &str) -> String {
+    if path == "stdout" {
+        panic!("Can't write outputs of several samples to stdout!");
+    }
+
+    let file_start = path.rfind('/').map_or(0, |i| i + 1);
+    match path[file_start..].find('.') {
+        Some(dot) => format!("{}.{}{}", &path[..file_start + dot], sample, &path[file_start + dot..]),
+        None => format!("{}.{}", path, sample),
+    }
+}
@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+
+use itertools::Itertools;
+
+use crate::reference::{Mismatch, Pattern};
+
+const BASES: [u8; 4] = [b'A', b'C', b'G', b'T'];
+
+// neighbours are packed two bits a base after a leading 1, so can't be longer than this
+const MAX_PACKED: usize = 63;
+
+fn plain(seq: &[u8]) -> bool {
+    seq.iter().all(|base| BASES.contains(base))
+}
+
+/// Packs a plain sequence, keeping its length in the position of the leading 1
+fn pack(seq: impl IntoIterator<Item = u8>) -> u128 {
+    seq.into_iter().fold(1, |packed, base| {
+        packed << 2 | BASES.iter().position(|b| *b == base).expect("Bad base!") as u128
+    })
+}
+
+/// Every sequence one substitution, insertion or deletion away, packed
+fn neighbours(seq: &[u8]) -> Vec<u128> {
+    let mut neighbours = Vec::new();
+    for i in 0..=seq.len() {
+        let (before, after) = seq.split_at(i);
+        for base in BASES {
+            neighbours.push(pack(before.iter().copied().chain([base]).chain(after.iter().copied())));
+            if i < seq.len() && seq[i] != base {
+                neighbours.push(pack(before.iter().copied().chain([base]).chain(after[1..].iter().copied())));
+            }
+        }
+        if i < seq.len() {
+            neighbours.push(pack(before.iter().chain(&after[1..]).copied()));
+        }
+    }
+    neighbours
+}
+
+/// The sequences of one region of every guide, and optionally those one edit from them
+#[derive(Default)]
+struct ExactRegion {
+    exact: HashMap<Vec<u8>, Vec<usize>>,
+    // sorted, as there are far too many for a hash map to build quickly
+    neighbours: Vec<(u128, usize)>,
+    // lengths of the sequences of each, to know which windows of a read to look up
+    exact_lengths: Vec<usize>,
+    neighbour_lengths: Vec<usize>,
+    // length of each guide's sequence, by index
+    lengths: HashMap<usize, usize>,
+}
+
+impl ExactRegion {
+    fn new(seqs: &[(usize, &[u8])], with_neighbours: bool) -> Self {
+        let mut region = ExactRegion::default();
+        for (i, seq) in seqs {
+            region.exact.entry(seq.to_vec()).or_insert_with(Vec::new).push(*i);
+            region.lengths.insert(*i, seq.len());
+            if with_neighbours {
+                region.neighbours.extend(neighbours(seq).into_iter().map(|neighbour| (neighbour, *i)));
+                region.neighbour_lengths.extend([seq.len() - 1, seq.len(), seq.len() + 1]);
+            }
+        }
+        region.neighbours.sort_unstable();
+        region.neighbours.dedup();
+        region.exact_lengths = region.exact.keys().map(Vec::len).unique().collect_vec();
+        region.neighbour_lengths = region.neighbour_lengths.into_iter().filter(|len| *len > 0).unique().collect_vec();
+        region
+    }
+
+    /// The guides whose sequence is somewhere in a read's region, and with neighbours, those one edit off it.
+    /// Like a Myers search, only guides allowing an edit at the error rate are let off by one
+    fn dists(&self, seq: &[u8], error_rate: f32, with_neighbours: bool) -> HashMap<usize, usize> {
+        let mut dists: HashMap<usize, usize> = self.exact_lengths.iter()
+            .flat_map(|len| seq.windows(*len))
+            .filter_map(|window| self.exact.get(window))
+            .flatten()
+            .map(|i| (*i, 0))
+            .collect();
+
+        if with_neighbours {
+            for window in self.neighbour_lengths.iter().flat_map(|len| seq.windows(*len)) {
+                let packed = pack(window.iter().copied());
+                let start = self.neighbours.partition_point(|(neighbour, _)| *neighbour < packed);
+                let guides = self.neighbours[start..].iter()
+                    .take_while(|(neighbour, _)| *neighbour == packed)
+                    .map(|(_, i)| *i);
+                for i in guides {
+                    if (error_rate * self.lengths[&i] as f32).floor() >= 1.0 {
+                        dists.entry(i).or_insert(1);
+                    }
+                }
+            }
+        }
+        dists
+    }
+}
+
+/// Hash maps of the exact sequences of every guide, to find the guides a read matches perfectly without
+/// searching for each. With neighbours, reads one edit off their guide are found the same way.
+/// Reads it can't settle for certain are left to the Myers search, so the results are the same either way
+pub struct ExactGuides {
+    names: Vec<String>,
+    spacers: ExactRegion,
+    extensions: ExactRegion,
+    nickings: ExactRegion,
+    with_neighbours: bool,
+    // the longest spacer, extension and nicking of any guide, which has the lowest error rate of any 2 edits
+    max_len: usize,
+}
+
+impl ExactGuides {
+    /// Indexes the guides, unless they have degenerate bases or a separate RTT and PBS,
+    /// which can't be matched by looking them up
+    pub fn new(
+        spacers: &HashMap<String, Pattern>,
+        extensions: &HashMap<String, Pattern>,
+        nickings: &HashMap<String, Pattern>,
+        with_neighbours: bool
+    ) -> Option<Self> {
+        /// The sequence of each guide which has one, by its index
+        fn region<'a>(names: &[String], patterns: &'a HashMap<String, Pattern>) -> Vec<(usize, &'a [u8])> {
+            names.iter().enumerate()
+                .filter_map(|(i, name)| Some((i, &patterns.get(name)?.seq[..])))
+                .collect_vec()
+        }
+
+        let names = spacers.keys().cloned().collect_vec();
+        let (spacer_seqs, extension_seqs, nicking_seqs) =
+            (region(&names, spacers), region(&names, extensions), region(&names, nickings));
+
+        let every_seq = spacer_seqs.iter().chain(&extension_seqs).chain(&nicking_seqs);
+        if extension_seqs.len() < names.len() || !every_seq.clone().all(|(_, seq)| !seq.is_empty() && plain(seq)) {
+            if with_neighbours {
+                eprintln!("Warning: ignoring --exact-neighbours, as guides with degenerate bases or a separate RTT and PBS can't be looked up!");
+            }
+            return None;
+        }
+        // a guide one edit off is only certainly the best if every guide's neighbours are known
+        let longest = every_seq.clone().map(|(_, seq)| seq.len()).max().unwrap_or(0);
+        if with_neighbours && longest >= MAX_PACKED {
+            eprintln!("Warning: ignoring --exact-neighbours, as a guide has a {} nt region, and only regions under {} nt can be looked up with their neighbours!",
+                longest, MAX_PACKED);
+        }
+        let with_neighbours = with_neighbours && longest < MAX_PACKED;
+
+        let mut lengths = vec![0; names.len()];
+        for (i, seq) in every_seq {
+            lengths[*i] += seq.len();
+        }
+        let max_len = lengths.into_iter().max().unwrap_or(0);
+
+        Some(ExactGuides {
+            spacers: ExactRegion::new(&spacer_seqs, with_neighbours),
+            extensions: ExactRegion::new(&extension_seqs, with_neighbours),
+            nickings: ExactRegion::new(&nicking_seqs, with_neighbours),
+            names,
+            with_neighbours,
+            max_len,
+        })
+    }
+
+    /// Every guide with the lowest error rate, exactly as `match_reference_all_quickly` would find them,
+    /// or None if the Myers search is needed to be sure
+    pub fn matches(
+        &self,
+        spacer_seq: &[u8],
+        extension_seq: &[u8],
+        nicking_seq: Option<&[u8]>,
+        error_rate: f32
+    ) -> Option<Vec<(&str, Mismatch)>> {
+        // Ns in the read match anything, which a lookup can't do
+        if !plain(spacer_seq) || !plain(extension_seq) || !nicking_seq.is_none_or(plain) {
+            return None;
+        }
+
+        let found = |with_neighbours: bool| {
+            let spacers = self.spacers.dists(spacer_seq, error_rate, with_neighbours);
+            let extensions = self.extensions.dists(extension_seq, error_rate, with_neighbours);
+            let nickings = nicking_seq.map(|seq| self.nickings.dists(seq, error_rate, with_neighbours));
+
+            spacers.into_iter()
+                .filter_map(|(i, spacer_dist)| {
+                    let extension_dist = extensions.get(&i)?;
+                    let mut mismatch = Mismatch::new(self.spacers.lengths[&i], spacer_dist)
+                        + Mismatch::new(self.extensions.lengths[&i], *extension_dist);
+                    // guides without a nicking sgRNA, or reads without a nicking region, skip it
+                    if let (Some(nickings), Some(len)) = (&nickings, self.nickings.lengths.get(&i)) {
+                        mismatch = mismatch + Mismatch::new(*len, *nickings.get(&i)?);
+                    }
+                    (mismatch.dist <= 1).then_some((&self.names[i][..], mismatch))
+                })
+                .collect_vec()
+        };
+
+        let perfect = found(false);
+        if !perfect.is_empty() {
+            return Some(perfect);
+        }
+        if !self.with_neighbours {
+            return None;
+        }
+
+        // guides one edit off are only certainly the best if no guide could do better with two
+        let found = found(true);
+        let best = found.iter().map(|(_, mismatch)| *mismatch).min()?;
+        if best >= Mismatch::new(self.max_len, 2) {
+            return None;
+        }
+        Some(found.into_iter().filter(|(_, mismatch)| *mismatch <= best).collect_vec())
+    }
+}
@@ -1,16 +1,28 @@
 use std::collections::HashMap;
 
-use clap::error;
+use clap::{error, ValueEnum};
 use itertools::Itertools;
 use rayon::iter::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator};
-use crate::reference::{EfficientGuide, EfficientGuides, FinalGuides, Mismatch, Pattern, Ref};
+use serde::Serialize;
+use crate::reference::{EfficientGuide, EfficientGuides, EfficientRegion, FinalGuides, end_distance, Mismatch, Pattern, Ref};
+
+/// The regions of a read, found between the cys4 and scaffold sequences
+#[derive(Copy, Clone, Debug)]
+pub struct Regions<'a> {
+    pub spacer: &'a [u8],
+    pub extension: &'a [u8],
+    // reads without a nicking guide have no nicking region
+    pub nicking: Option<&'a [u8]>,
+    // whatever follows the last scaffold
+    pub tail: &'a [u8],
+}
 
 pub fn split_cys4_regions<'a>(
     seq: &'a [u8], 
     reference: &Ref,
     error_rate: f32
 ) -> Option<(&'a [u8], &'a [u8])> {
-    let mut matches = reference.cys4.clone().get_matches(seq, error_rate);
+    let mut matches = reference.cys4.get_matches(seq, error_rate);
 
     // get them in order
     matches.sort_by_key(|(start, _, _)| *start);
@@ -24,15 +36,15 @@ pub fn split_cys4_regions<'a>(
 
 fn split_scaffold_regions<'a>(
     seq: &'a [u8], reference: &Ref, error_rate: f32
-) -> Option<(&'a [u8], &'a [u8])> {
-    let mut matches = reference.scaffold.clone().get_matches(seq, error_rate);
+) -> Option<(&'a [u8], &'a [u8], &'a [u8])> {
+    let mut matches = reference.scaffold.get_matches(seq, error_rate);
 
     // get them in order
     matches.sort_by_key(|(start, _, _)| *start);
     
     match &matches[..] {
-        &[(start1, end1, _), (start2, _, _)] => 
-            Some((&seq[..start1], &seq[end1+1..start2])),
+        &[(start1, end1, _), (start2, end2, _)] => 
+            Some((&seq[..start1], &seq[end1+1..start2], &seq[end2..])),
         // also catch cases where there was just one
         // &[(start1, end1, _)] => 
         //     Some((&seq[..start1], &seq[end1+1..])),
@@ -40,10 +52,22 @@ fn split_scaffold_regions<'a>(
     }
 }
 
+fn split_scaffold_region<'a>(
+    seq: &'a [u8], reference: &Ref, error_rate: f32
+) -> Option<(&'a [u8], &'a [u8])> {
+    let matches = reference.scaffold.get_matches(seq, error_rate);
+
+    match &matches[..] {
+        &[(start, end, _)] => 
+            Some((&seq[..start], &seq[end..])),
+        _ => None
+    }
+}
+
 // pub fn match_reference_all(
 //     spacer_seq: &[u8], 
 //     extension_seq: &[u8], 
-//     nicking_seq: &[u8], 
+//     nicking_seq: Option<&[u8]>, 
 //     efficient_guides: &EfficientGuides,
 //     error_rate: f32
 // ) -> Vec<String> {
@@ -91,82 +115,232 @@ fn split_scaffold_regions<'a>(
 //     names
 // }
 
-pub fn match_reference_all_carefully<'a>(
+fn all_matching_names<'a>(
+    seq: &[u8], 
+    patterns: &'a EfficientRegion,
+    error_rate: f32
+) -> HashMap<&'a str, Mismatch> {
+
+    let tolerance = 0.1;
+
+    let all_matches = patterns.candidates(seq, error_rate).into_iter()
+        .filter_map(|EfficientGuide { pattern, names }|
+            pattern.get_best_match(seq, error_rate)
+                .map(|a| (names, a)))
+                .sorted_by(|(_, dist), (_, b_dist)| dist.partial_cmp(b_dist).unwrap());
+                // .sorted_by_key(|(_, dist)| *dist);
+    
+    if let Some((_, best_dist)) = all_matches.clone().next() {
+        all_matches
+            .take_while(|(_, dist)| dist.error_rate() <= best_dist.error_rate() + tolerance)
+            .flat_map(|(v, dist)| v.iter().map(|n| (&n[..], dist)).collect_vec())
+            .collect()
+    } else {
+        HashMap::new()
+    }
+}
+
+/// Keeps only the names with the lowest error rate
+fn best_names<'a>(names: &[(&'a str, Mismatch)]) -> Vec<(&'a str, Mismatch)> {
+    if let Some((_, best_dist)) = names.iter().min_by(|(_,dist), (_, b_dist)| dist.partial_cmp(b_dist).unwrap()) {
+        names.iter().filter(|(_, dist)| dist <= best_dist).copied().collect_vec()
+    } else {
+        Vec::new()
+    }
+}
+
+fn match_carefully<'a>(
     spacer_seq: &[u8], 
     extension_seq: &[u8], 
-    nicking_seq: &[u8],
+    nicking_seq: Option<&[u8]>,
     efficient_guides: &'a EfficientGuides,
-    error_rate: f32
+    error_rate: f32,
+    with_rtt: bool
 ) -> Vec<(&'a str, Mismatch)> {
     // println!("handling seqs {}, {}, {}", _seq_to_string(&spacer_seq), _seq_to_string(&extension_seq), _seq_to_string(&nicking_seq));
 
-    fn all_matching_names<'a>(
-        seq: &[u8], 
-        patterns: &'a [EfficientGuide],
-        error_rate: f32
-    ) -> HashMap<&'a str, Mismatch> {
-
-        let tolerance = 0.1;
-
-        let all_matches = patterns.iter()
-            .filter_map(|EfficientGuide { pattern, names }|
-                pattern.clone().get_matches(seq, error_rate)
-                    .into_iter()
-                    .min_by(|(_, _, dist), (_, _, b_dist)| dist.partial_cmp(b_dist).unwrap())
-                    .map(|a| (names, a)))
-                    .sorted_by(|(_,(_, _, dist)), (_,(_, _, b_dist))| dist.partial_cmp(b_dist).unwrap());
-                    // .sorted_by_key(|(_, (_, _, dist))| *dist);
-        
-        if let Some((_, (_, _, best_dist))) = all_matches.clone().next() {
-            all_matches
-                .take_while(|(_, (_, _, dist))| dist.error_rate() <= best_dist.error_rate() + tolerance)
-                .flat_map(|(v, (_, _, dist))| v.iter().map(|n| (&n[..], dist)).collect_vec())
-                .collect()
+    let best_spacer_names = 
+        all_matching_names(spacer_seq, &efficient_guides.spacers, error_rate);
+    let best_nicking_names = nicking_seq
+        .map(|nicking_seq| all_matching_names(nicking_seq, &efficient_guides.nickings, error_rate));
+    let best_pbs_names = 
+        all_matching_names(extension_seq, &efficient_guides.primer_binding_sites, error_rate);
+    let (best_extension_names, best_rtt_names) = if with_rtt {
+        (all_matching_names(extension_seq, &efficient_guides.extensions, error_rate),
+            all_matching_names(extension_seq, &efficient_guides.rt_templates, error_rate))
+    } else {
+        (HashMap::new(), HashMap::new())
+    };
+
+    let mut final_names = Vec::new();
+    for (spacer_name, spacer_mismatch) in best_spacer_names {
+        // guides with a separate RTT and PBS have each matched independently
+        let extension_mismatch = if with_rtt {
+            best_extension_names.get(&spacer_name).copied().or_else(|| 
+                Some(*best_rtt_names.get(&spacer_name)? + *best_pbs_names.get(&spacer_name)?))
         } else {
-            HashMap::new()
+            best_pbs_names.get(&spacer_name).copied()
+        };
+
+        // guides without a nicking sgRNA, or reads without a nicking region, skip it
+        let nicking_mismatch = match &best_nicking_names {
+            Some(best_nicking_names) if !efficient_guides.unnicked.contains(spacer_name) => 
+                best_nicking_names.get(&spacer_name).copied(),
+            _ => Some(Mismatch::new(0, 0)),
+        };
+
+        if let (Some(extension_mismatch), Some(nicking_mismatch)) = (extension_mismatch, nicking_mismatch) {
+            final_names.push((spacer_name, spacer_mismatch + extension_mismatch + nicking_mismatch));
         }
     }
 
-    let best_spacer_names = 
-        all_matching_names(spacer_seq, &efficient_guides.spacers, error_rate);
-    let best_extension_names = 
-        all_matching_names(extension_seq, &efficient_guides.extensions, error_rate);
-    let best_nicking_names = 
-        all_matching_names(nicking_seq, &efficient_guides.nickings, error_rate);
+    best_names(&final_names)
+}
+
+pub fn match_reference_all_carefully<'a>(
+    spacer_seq: &[u8], 
+    extension_seq: &[u8], 
+    nicking_seq: Option<&[u8]>,
+    efficient_guides: &'a EfficientGuides,
+    error_rate: f32
+) -> Vec<(&'a str, Mismatch)> {
+    match_carefully(spacer_seq, extension_seq, nicking_seq, efficient_guides, error_rate, true)
+}
 
-    let mut final_names = HashMap::new();
-    for (spacer_name, Mismatch { len: spacer_len, dist: spacer_dist }) in best_spacer_names {
-        if let Some(Mismatch { len: extension_len, dist: extension_dist }) = best_extension_names.get(&spacer_name) {
-            if let Some(Mismatch { len: nicking_len, dist: nicking_dist } ) = best_nicking_names.get(&spacer_name) {
+/// Like `match_reference_all_carefully`, but only considers guides with a separate RTT and PBS, 
+/// and ignores their RTT
+pub fn match_reference_pbs_carefully<'a>(
+    spacer_seq: &[u8], 
+    extension_seq: &[u8], 
+    nicking_seq: Option<&[u8]>,
+    efficient_guides: &'a EfficientGuides,
+    error_rate: f32
+) -> Vec<(&'a str, Mismatch)> {
+    if efficient_guides.primer_binding_sites.is_empty() {
+        return Vec::new();
+    }
 
-                final_names.insert(spacer_name, Mismatch::new(spacer_len + extension_len + nicking_len, spacer_dist + extension_dist + nicking_dist));
-            }
-        }
+    match_carefully(spacer_seq, extension_seq, nicking_seq, efficient_guides, error_rate, false)
+}
+
+/// The guides whose regions best match each region of a chimeric read
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Debug, Default)]
+pub struct ChimeraParents {
+    pub spacer: Vec<String>,
+    pub extension: Vec<String>,
+    pub nicking: Vec<String>,
+}
+
+impl ChimeraParents {
+    /// The guides of each region, with ties joined by commas and unmatched regions as -
+    pub fn regions(&self) -> [String; 3] {
+        [&self.spacer, &self.extension, &self.nicking]
+            .map(|names| if names.is_empty() { String::from("-") } else { names.join(",") })
     }
 
-    if let Some((_, best_dist)) = final_names.clone().into_iter().min_by(|(_,dist), (_, b_dist)| dist.partial_cmp(b_dist).unwrap()) {
-        final_names.into_iter().filter(|(_, dist)| *dist <= best_dist).collect_vec()
-    } else {
-        Vec::new()
+    /// Identifies the chimera, like g1/g5/g1
+    pub fn signature(&self) -> String {
+        self.regions().join("/")
+    }
+
+    /// Every guide which contributed a region
+    pub fn guides(&self) -> Vec<&str> {
+        [&self.spacer, &self.extension, &self.nicking].into_iter()
+            .flatten()
+            .map(|name| &name[..])
+            .unique()
+            .collect_vec()
     }
 }
 
-fn best_matches(matches: &[(usize, usize, Mismatch)], tolerance: &f32) -> Vec<(usize, usize, Mismatch)> {
-    if let Some((_, _, best)) = matches.to_owned().iter()
-        .min_by(|(_, _, d1), (_, _, d2)| d1.partial_cmp(d2).unwrap()) {
-        
-        matches.iter()
-            .filter(|(_, _, dist)| dist.error_rate() <= best.error_rate() + tolerance)
-            .map(|a| a.to_owned()).collect_vec()
+/// Names of the guides with the lowest error rate in one region, across several sets of patterns
+fn best_region_names(
+    seq: &[u8],
+    patterns: &[&EfficientRegion],
+    error_rate: f32
+) -> Vec<String> {
+    let names = patterns.iter()
+        .flat_map(|patterns| all_matching_names(seq, patterns, error_rate))
+        .collect_vec();
+
+    best_names(&names).into_iter()
+        .map(|(name, _)| name)
+        .sorted()
+        .dedup()
+        .map(String::from)
+        .collect_vec()
+}
+
+/// Finds the guides each region of a read came from, independently of each other
+pub fn chimera_parents(
+    regions: &Regions,
+    efficient_guides: &EfficientGuides,
+    error_rate: f32
+) -> ChimeraParents {
+    ChimeraParents {
+        spacer: best_region_names(regions.spacer, &[&efficient_guides.spacers], error_rate),
+        // guides with a separate RTT and PBS are told apart by their RTT
+        extension: best_region_names(regions.extension, 
+            &[&efficient_guides.extensions, &efficient_guides.rt_templates], error_rate),
+        nicking: regions.nicking
+            .map(|nicking| best_region_names(nicking, &[&efficient_guides.nickings], error_rate))
+            .unwrap_or_default(),
+    }
+}
+
+fn f_b<'a>(
+    name_mismatch: &(&'a str, Mismatch), 
+    guides: &HashMap<String, Pattern>, 
+    seq: &[u8], 
+    error_rate: f32
+) -> Option<(&'a str, Mismatch)> {
+    let (name, mismatch) = name_mismatch;
+    
+    let pattern = guides.get(*name)?;
+    
+    pattern.get_best_match(seq, error_rate).map(|m| (
+        *name,
+        Mismatch {
+            len: mismatch.len + m.len,
+            dist: mismatch.dist + m.dist,
+        }
+    ))
+}
+
+fn f_extension<'a>(
+    name_mismatch: &(&'a str, Mismatch), 
+    guides: &FinalGuides, 
+    seq: &[u8], 
+    error_rate: f32
+) -> Option<(&'a str, Mismatch)> {
+    if guides.rt_templates.contains_key(name_mismatch.0) {
+        // guides with a separate RTT and PBS have each matched independently
+        f_b(name_mismatch, &guides.rt_templates, seq, error_rate)
+            .and_then(|name_mismatch| 
+                f_b(&name_mismatch, &guides.primer_binding_sites, seq, error_rate))
     } else {
-        vec![]
+        f_b(name_mismatch, &guides.extensions, seq, error_rate)
+    }
+}
+
+fn f_nicking<'a>(
+    name_mismatch: &(&'a str, Mismatch), 
+    guides: &FinalGuides, 
+    seq: Option<&[u8]>, 
+    error_rate: f32
+) -> Option<(&'a str, Mismatch)> {
+    match seq {
+        Some(seq) if guides.nickings.contains_key(name_mismatch.0) => 
+            f_b(name_mismatch, &guides.nickings, seq, error_rate),
+        // guides without a nicking sgRNA, or reads without a nicking region, skip it
+        _ => Some(*name_mismatch),
     }
 }
 
 pub fn match_reference_all_quickly<'a>(
     spacer_seq: &[u8], 
     extension_seq: &[u8], 
-    nicking_seq: &[u8],
+    nicking_seq: Option<&[u8]>,
     guides: &'a FinalGuides,
     error_rate: f32
 ) -> Vec<(&'a str, Mismatch)> {
@@ -182,33 +356,11 @@ pub fn match_reference_all_quickly<'a>(
         
         let pattern = guides.get(*name)?;
         
-        match &best_matches(&pattern.get_matches(seq, error_rate), &0.0)[..] {
-            [] => None,
-            [(_, _, first), ..] => Some((
-                name, 
-                Mismatch { 
-                    len: mismatch.len + first.len, 
-                    dist: mismatch.dist + first.dist 
-                }
-            )),
-        }
-    }
-
-    fn f_b<'a>(
-        name_mismatch: &(&'a str, Mismatch), 
-        guides: &HashMap<String, Pattern>, 
-        seq: &[u8], 
-        error_rate: f32
-    ) -> Option<(&'a str, Mismatch)> {
-        let (name, mismatch) = name_mismatch;
-        
-        let pattern = guides.get(*name)?;
-        
-        pattern.get_best_match(seq, error_rate).map(|m| (
-            *name,
-            Mismatch {
-                len: mismatch.len + m.len,
-                dist: mismatch.dist + m.dist,
+        pattern.get_best_match(seq, error_rate).map(|first| (
+            *name, 
+            Mismatch { 
+                len: mismatch.len + first.len, 
+                dist: mismatch.dist + first.dist 
             }
         ))
     }
@@ -220,20 +372,42 @@ pub fn match_reference_all_quickly<'a>(
     //     .flat_map(|name| f_a(&name, &guides.spacers, spacer_seq, error_rate*0.5)).collect();
 
     // all names of sequences
-    let final_names: Vec<_> = guides.spacers.keys()
-        .map(|name| (&name[..], Mismatch::new(0, 0)))
+    // reads matching their guide perfectly can just be looked up
+    if let Some(names) = guides.exact_matches(spacer_seq, extension_seq, nicking_seq, error_rate) {
+        return names;
+    }
+
+    // only the guides whose spacers share enough k-mers with the read could match it
+    let final_names: Vec<_> = guides.spacer_candidates(spacer_seq, error_rate).into_iter()
+        .map(|name| (name, Mismatch::new(0, 0)))
         .flat_map(|name| f_b(&name, &guides.spacers, spacer_seq, error_rate))
         .flat_map(|name_mismatch| 
-            f_b(&name_mismatch, &guides.extensions, extension_seq, error_rate))
+            f_extension(&name_mismatch, guides, extension_seq, error_rate))
         .flat_map(|name_mismatch| 
-            f_b(&name_mismatch, &guides.nickings, nicking_seq, error_rate)).collect();
+            f_nicking(&name_mismatch, guides, nicking_seq, error_rate)).collect();
 
-    if let Some((_, best)) = final_names.iter().min_by_key(|(_, dist)| dist) {
-        final_names.iter().filter(|(_, dist)| dist <= best)
-            .map(|(name, mismatch)| (*name, mismatch.to_owned())).collect()
-    } else {
-        vec![]
-    }
+    best_names(&final_names)
+}
+
+/// Like `match_reference_all_quickly`, but only considers guides with a separate RTT and PBS, 
+/// and ignores their RTT
+pub fn match_reference_pbs_quickly<'a>(
+    spacer_seq: &[u8], 
+    extension_seq: &[u8], 
+    nicking_seq: Option<&[u8]>,
+    guides: &'a FinalGuides,
+    error_rate: f32
+) -> Vec<(&'a str, Mismatch)> {
+    let final_names: Vec<_> = guides.spacer_candidates(spacer_seq, error_rate).into_iter()
+        .filter(|name| guides.primer_binding_sites.contains_key(*name))
+        .map(|name| (name, Mismatch::new(0, 0)))
+        .flat_map(|name| f_b(&name, &guides.spacers, spacer_seq, error_rate))
+        .flat_map(|name_mismatch| 
+            f_b(&name_mismatch, &guides.primer_binding_sites, extension_seq, error_rate))
+        .flat_map(|name_mismatch| 
+            f_nicking(&name_mismatch, guides, nicking_seq, error_rate)).collect();
+
+    best_names(&final_names)
 }
 
 #[derive(Clone, PartialEq, Eq, Hash, Debug, Default)]
@@ -241,11 +415,52 @@ pub enum StructureResult<'a> {
     // (.. scaf .. cys4 .. scaf ..) structure can be found
     WellStructured(RefResult<'a>),
 
+    // the structure can be found, but the epegRNA linker or 3' motif after the PBS is missing or truncated
+    MotifTruncated(RefResult<'a>),
+
     // no structure can be found
     #[default]
     BadlyStructured,
 }
 
+/// Classes which every read falls into exactly one of, as named in the stats and as shown, in the order they're stacked
+pub const CLASSES: [(&str, &str); 6] = [
+    ("valid", "Valid"),
+    ("chimeric", "Chimeric"),
+    ("recombinedrtt", "Recombined RTT"),
+    ("ambiguous", "Ambiguous"),
+    ("motiftruncated", "Motif-truncated"),
+    ("badlystructured", "Badly structured"),
+];
+
+impl<'a> StructureResult<'a> {
+    /// The guide this read was assigned to, if any
+    pub fn guide(&self) -> Option<&'a str> {
+        match self {
+            StructureResult::WellStructured(r) | StructureResult::MotifTruncated(r) => match r {
+                RefResult::Valid(name, _) | RefResult::RecombinedRtt(name, _) => Some(name),
+                RefResult::Chimera | RefResult::Ambiguous => None,
+            },
+            StructureResult::BadlyStructured => None,
+        }
+    }
+
+    /// The class this read is counted under, as named in the stats
+    pub fn class(&self) -> &'static str {
+        let (class, _) = CLASSES[match self {
+            StructureResult::WellStructured(r) => match r {
+                RefResult::Valid(_, _) => 0,
+                RefResult::Chimera => 1,
+                RefResult::RecombinedRtt(_, _) => 2,
+                RefResult::Ambiguous => 3,
+            },
+            StructureResult::MotifTruncated(_) => 4,
+            StructureResult::BadlyStructured => 5,
+        }];
+        class
+    }
+}
+
 #[derive(Clone, PartialEq, Eq, Hash, Debug)]
 pub enum RefResult<'a> {
     // there is exactly one best (spacer, extension, nicking) matching a valid triple from the reference
@@ -253,22 +468,25 @@ pub enum RefResult<'a> {
 
     // there are no best (spacer, extension, nicking) matching a triple from the reference
     Chimera,
+
+    // there is exactly one best (spacer, PBS, nicking) matching a guide from the reference, but its RTT doesn't match
+    RecombinedRtt(&'a str, Mismatch),
     
     // there are multiple best (spacer, extension, nicking) which match different reference triples
     Ambiguous,
 }
 
 pub fn structure_classify_carefully<'a>(
-    seq: &[u8],
+    regions: Option<Regions>,
     reference: &Ref,
     efficient_guides: &'a EfficientGuides,
     error_rate: f32
 ) -> StructureResult<'a> {
-    match break_into_regions(seq, reference, error_rate) {
-        Some((spacer_seq, extension_seq, nicking_seq)) => 
-            StructureResult::WellStructured(reference_classify_carefully(
-                spacer_seq, extension_seq, nicking_seq, efficient_guides, error_rate
-            )),
+    match regions {
+        Some(Regions { spacer, extension, nicking, .. }) => 
+            motif_classify(reference_classify_carefully(
+                spacer, extension, nicking, efficient_guides, error_rate
+            ), extension, reference, error_rate),
         None =>
             StructureResult::BadlyStructured,
     }
@@ -277,52 +495,138 @@ pub fn structure_classify_carefully<'a>(
 pub fn reference_classify_carefully<'a>(
     spacer_seq: &[u8], 
     extension_seq: &[u8], 
-    nicking_seq: &[u8], 
+    nicking_seq: Option<&[u8]>, 
     efficient_guides: &'a EfficientGuides,
     error_rate: f32
 ) -> RefResult<'a> {
     match &match_reference_all_carefully(spacer_seq, extension_seq, nicking_seq, efficient_guides, error_rate)[..] {
-        [] => RefResult::Chimera,
+        [] => match &match_reference_pbs_carefully(spacer_seq, extension_seq, nicking_seq, efficient_guides, error_rate)[..] {
+            [(name, error)] => RefResult::RecombinedRtt(name, *error),
+            _ => RefResult::Chimera,
+        },
         [(name, error)] => RefResult::Valid(name, error.clone()),
         _ => RefResult::Ambiguous
     }
 }
 
 pub fn structure_classify_quickly<'a>(
-    seq: &[u8],
+    regions: Option<Regions>,
     reference: &Ref,
     final_guides: &'a FinalGuides,
     error_rate: f32
 ) -> StructureResult<'a> {
-    match break_into_regions(seq, reference, error_rate) {
-        Some((spacer_seq, extension_seq, nicking_seq)) => 
-            StructureResult::WellStructured(reference_classify_quickly(
-                spacer_seq, extension_seq, nicking_seq, final_guides, error_rate
-            )),
+    match regions {
+        Some(Regions { spacer, extension, nicking, .. }) => 
+            motif_classify(reference_classify_quickly(
+                spacer, extension, nicking, final_guides, error_rate
+            ), extension, reference, error_rate),
         None =>
             StructureResult::BadlyStructured,
     }
 }
 
+/// How reads are matched against the guides
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mode {
+    // the best match of each region, chained by name
+    Quick,
+    // every near-best match of each region
+    Careful,
+    // quick, then careful for the reads quick mode calls chimeric or ambiguous
+    Adaptive,
+}
+
+impl Mode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Mode::Quick => "quick",
+            Mode::Careful => "careful",
+            Mode::Adaptive => "adaptive",
+        }
+    }
+}
+
+/// Classifies quickly, and escalates reads called chimeric, ambiguous or recombined to the careful algorithm,
+/// since quick mode over-calls them. Also gives the mode which decided the read
+pub fn structure_classify_adaptively<'a>(
+    regions: Option<Regions>,
+    reference: &Ref,
+    efficient_guides: &'a EfficientGuides,
+    final_guides: &'a FinalGuides,
+    error_rate: f32
+) -> (StructureResult<'a>, Mode) {
+    match structure_classify_quickly(regions, reference, final_guides, error_rate) {
+        StructureResult::WellStructured(RefResult::Chimera | RefResult::Ambiguous | RefResult::RecombinedRtt(..))
+        | StructureResult::MotifTruncated(RefResult::Chimera | RefResult::Ambiguous | RefResult::RecombinedRtt(..)) =>
+            (structure_classify_carefully(regions, reference, efficient_guides, error_rate), Mode::Careful),
+        quick => (quick, Mode::Quick),
+    }
+}
+
 pub fn reference_classify_quickly<'a>(
     spacer_seq: &[u8], 
     extension_seq: &[u8], 
-    nicking_seq: &[u8], 
+    nicking_seq: Option<&[u8]>, 
     final_guides: &'a FinalGuides,
     error_rate: f32
 ) -> RefResult<'a> {
     match &match_reference_all_quickly(spacer_seq, extension_seq, nicking_seq, final_guides, error_rate)[..] {
-        [] => RefResult::Chimera,
+        [] => match &match_reference_pbs_quickly(spacer_seq, extension_seq, nicking_seq, final_guides, error_rate)[..] {
+            [(name, error)] => RefResult::RecombinedRtt(name, *error),
+            _ => RefResult::Chimera,
+        },
         [(name, error)] => RefResult::Valid(*name, *error),
         _ => RefResult::Ambiguous
     }
 }
 
+/// Checks that the linker and 3' motif expected after the PBS are intact, running from the end of the
+/// guide's PBS (or extension) to the end of the region within the motif error rate.
+/// Reads which weren't assigned a guide are checked against the default linker and motif, anywhere before the end
+pub fn motif_classify<'a>(
+    result: RefResult<'a>,
+    extension_seq: &[u8],
+    reference: &Ref,
+    error_rate: f32
+) -> StructureResult<'a> {
+    let name = match result {
+        RefResult::Valid(name, _) | RefResult::RecombinedRtt(name, _) => Some(name),
+        RefResult::Chimera | RefResult::Ambiguous => None,
+    };
+
+    let (linker, motif) = reference.linker_motif(name);
+    let tail = linker.into_iter().chain(motif)
+        .flat_map(|pattern| pattern.seq.iter().copied())
+        .collect_vec();
+    if tail.is_empty() {
+        return StructureResult::WellStructured(result);
+    }
+
+    // where the guide's PBS ends, preferring the latest of equally good matches
+    let pbs_end = name.and_then(|name| reference.guide(name))
+        .map(|guide| guide.pbs.as_ref().unwrap_or(&guide.extension))
+        .and_then(|pbs| pbs.get_match_ends(extension_seq, error_rate).into_iter()
+            .min_by_key(|(end, dist)| (*dist, std::cmp::Reverse(*end))))
+        .map(|(end, _)| end);
+
+    let dist = match pbs_end {
+        Some(end) => end_distance(&tail, &extension_seq[end..], false),
+        None => end_distance(&tail, extension_seq, true),
+    };
+
+    if dist as f32 <= reference.motif_error_rate * tail.len() as f32 {
+        StructureResult::WellStructured(result)
+    } else {
+        StructureResult::MotifTruncated(result)
+    }
+}
+
 /// Tries as best as possible to detect chimeras
 pub fn chimeric(
     spacer_seq: &[u8], 
     extension_seq: &[u8], 
-    nicking_seq: &[u8], 
+    nicking_seq: Option<&[u8]>, 
     efficient_guides: &EfficientGuides,
     error_rate: f32
 ) -> bool {
@@ -330,9 +634,13 @@ pub fn chimeric(
     match_reference_all_carefully(spacer_seq, extension_seq, nicking_seq, efficient_guides, error_rate).is_empty()
 }
 
-pub fn break_into_regions<'a>(seq: &'a [u8], reference: &Ref, error_rate: f32) -> Option<(&'a [u8], &'a [u8], &'a [u8])> {
+pub fn break_into_regions<'a>(seq: &'a [u8], reference: &Ref, error_rate: f32) -> Option<Regions<'a>> {
+    if !reference.nicking {
+        return break_into_regions_unnicked(seq, reference, error_rate);
+    }
+
     // first, find scaffolds. there should be two
-    let (before_scaffold, after_scaffold) = split_scaffold_regions(seq, reference, error_rate)?;
+    let (before_scaffold, after_scaffold, tail) = split_scaffold_regions(seq, reference, error_rate)?;
 
     // take the after-scaffold part, and split it by the location of cys4
     let (extension_seq, nicking_seq) = split_cys4_regions(after_scaffold, reference, error_rate)?;
@@ -340,12 +648,29 @@ pub fn break_into_regions<'a>(seq: &'a [u8], reference: &Ref, error_rate: f32) -
     // take the before-scaffold part, and split that too
     let (_, spacer_seq) = split_cys4_regions(before_scaffold, reference, error_rate)?;
 
-    Some((spacer_seq, extension_seq, nicking_seq))
+    Some(Regions { spacer: spacer_seq, extension: extension_seq, nicking: Some(nicking_seq), tail })
+}
+
+/// Breaks up reads which have no nicking guide, so only (.. cys4 .. scaf ..) 
+pub fn break_into_regions_unnicked<'a>(seq: &'a [u8], reference: &Ref, error_rate: f32) -> Option<Regions<'a>> {
+    // first, find the scaffold. there should be one
+    let (before_scaffold, after_scaffold) = split_scaffold_region(seq, reference, error_rate)?;
+
+    // the extension runs until the next cys4, if there is one
+    let (extension_seq, tail) = match split_cys4_regions(after_scaffold, reference, error_rate) {
+        Some((extension_seq, tail)) => (extension_seq, tail),
+        None => (after_scaffold, &after_scaffold[after_scaffold.len()..]),
+    };
+
+    // take the before-scaffold part, and split that too
+    let (_, spacer_seq) = split_cys4_regions(before_scaffold, reference, error_rate)?;
+
+    Some(Regions { spacer: spacer_seq, extension: extension_seq, nicking: None, tail })
 }
 
 pub fn break_into_regions_relaxed<'a>(seq: &'a [u8], reference: &Ref, error_rate: f32) -> Option<(&'a [u8], &'a [u8], &'a [u8])> {
     // first, find scaffolds. there should be two
-    let (spacer_seq, after_scaffold) = split_scaffold_regions(seq, reference, error_rate)?;
+    let (spacer_seq, after_scaffold, _) = split_scaffold_regions(seq, reference, error_rate)?;
 
     // take the after-scaffold part, and split it by the location of cys4
     let (extension_seq, nicking_seq) = split_cys4_regions(after_scaffold, reference, error_rate)?;    
@@ -360,10 +685,78 @@ pub fn quick_chimeric(
     error_rate: f32
 ) -> Option<bool> {
     // first, find scaffolds. there should be two
-    let (before_scaffold, after_scaffold) = split_scaffold_regions(seq, reference, error_rate)?;
+    let (before_scaffold, after_scaffold, _) = split_scaffold_regions(seq, reference, error_rate)?;
 
     // take the after-scaffold part, and split it by the location of cys4
     let (cys4_first, cys4_second) = split_cys4_regions(after_scaffold, reference, error_rate)?;
 
     todo!()
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LINKER: &[u8] = b"AGCGCTAA";
+    const TEVOPREQ1: &[u8] = b"CGCGGTTCTATCTAGTTACGCGTTAAACCAACTAGAA";
+    const EXTENSION: &[u8] = b"TGATCCGAAGTCCATGGCTTAGCAGTCGAT";
+
+    /// A one-guide epegRNA library with the tevopreQ1 motif
+    fn motif_reference() -> Ref {
+        let tsv = format!("g1\tGACTTCAGGCTAGCATCGAT\t{}\tCCTAGGATCGTACGTTAGCA\n", String::from_utf8_lossy(EXTENSION));
+        let mut reference = Ref::from_tsv(&tsv);
+        reference.linker = Some(Pattern::new(LINKER));
+        reference.motif = Some(Pattern::new(TEVOPREQ1));
+        reference
+    }
+
+    fn classify<'a>(result: RefResult<'a>, extension_seq: &[u8], reference: &Ref) -> StructureResult<'a> {
+        motif_classify(result, extension_seq, reference, 0.25)
+    }
+
+    fn valid() -> RefResult<'static> {
+        RefResult::Valid("g1", Mismatch::new(EXTENSION.len(), 0))
+    }
+
+    #[test]
+    fn intact_motif() {
+        let reference = motif_reference();
+        let read = [EXTENSION, LINKER, TEVOPREQ1].concat();
+
+        assert_eq!(classify(valid(), &read, &reference), StructureResult::WellStructured(valid()));
+        assert_eq!(classify(RefResult::Chimera, &read, &reference), StructureResult::WellStructured(RefResult::Chimera));
+    }
+
+    #[test]
+    fn motif_with_errors() {
+        let reference = motif_reference();
+        let mut read = [EXTENSION, LINKER, TEVOPREQ1].concat();
+        let motif_start = EXTENSION.len() + LINKER.len();
+        read[motif_start + 10] = b'A';
+        read.remove(motif_start + 20);
+
+        assert_eq!(classify(valid(), &read, &reference), StructureResult::WellStructured(valid()));
+    }
+
+    #[test]
+    fn truncated_motif() {
+        let reference = motif_reference();
+        for missing in [5, 9, 20, TEVOPREQ1.len()] {
+            let read = [EXTENSION, LINKER, &TEVOPREQ1[..TEVOPREQ1.len() - missing]].concat();
+
+            assert_eq!(classify(valid(), &read, &reference), StructureResult::MotifTruncated(valid()), "{} missing", missing);
+            assert_eq!(classify(RefResult::Chimera, &read, &reference), StructureResult::MotifTruncated(RefResult::Chimera));
+        }
+    }
+
+    #[test]
+    fn motif_not_after_pbs() {
+        let reference = motif_reference();
+        // the whole linker and motif, but truncated into the PBS
+        let read = [&EXTENSION[..EXTENSION.len() - 8], LINKER, TEVOPREQ1].concat();
+        assert_eq!(classify(valid(), &read, &reference), StructureResult::MotifTruncated(valid()));
+
+        // the whole linker and motif, but not running to the end of the extension
+        let read = [EXTENSION, LINKER, TEVOPREQ1, b"GATTACAGATTACA"].concat();
+        assert_eq!(classify(valid(), &read, &reference), StructureResult::MotifTruncated(valid()));
+    }
+}
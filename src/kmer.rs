@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+
+use itertools::Itertools;
+
+// q-grams are packed two bits a base, so they can't be longer than this
+const MAX_Q: usize = 16;
+// shorter q-grams are shared by chance with almost any sequence, so filter out too little to be worth it
+const MIN_Q: usize = 5;
+
+/// Packs a q-gram of plain bases, or gives up on degenerate bases and Ns
+fn encode(qgram: &[u8]) -> Option<u32> {
+    qgram.iter().try_fold(0, |code, base| {
+        let bits = match base {
+            b'A' => 0,
+            b'C' => 1,
+            b'G' => 2,
+            b'T' => 3,
+            _ => return None,
+        };
+        Some(code << 2 | bits)
+    })
+}
+
+/// Edits allowed against a pattern, as `Pattern::get_matches` works them out
+fn edit_dist(len: usize, error_rate: f32) -> usize {
+    (error_rate * (len as f32)).floor() as usize
+}
+
+/// How many of a pattern's q-grams survive k edits, which a sequence must share for the pattern to match it
+fn threshold(len: usize, q: usize, k: usize) -> i64 {
+    (len as i64 - q as i64 + 1) - (k * q) as i64
+}
+
+/// Shortlists the patterns which could match a sequence, by the q-grams they share with it.
+/// Each edit touches at most q of a pattern's q-grams, so a match with k edits shares at least
+/// m - q + 1 - kq of them (the q-gram lemma). Patterns which fail that can't match, so leaving them
+/// out gives exactly the results of checking every pattern. At error rates too high to leave typical
+/// patterns any q-grams to share, nothing is indexed and every pattern is checked
+#[derive(Clone)]
+pub struct KmerIndex {
+    // None when nothing is indexed
+    q: Option<usize>,
+    // length of a typical pattern, which q was chosen for
+    median: usize,
+    // the error rate the thresholds were worked out for, and any lower
+    error_rate: f32,
+    // for each q-gram, the patterns with it and at how many positions
+    postings: HashMap<u32, Vec<(usize, u32)>>,
+    thresholds: Vec<i64>,
+    // patterns too short, too degenerate or allowing too many edits to filter, which are always checked
+    unfiltered: Vec<usize>,
+}
+
+impl KmerIndex {
+    pub fn new(patterns: &[&[u8]], error_rate: f32) -> Self {
+        // the longest q-grams which still leave a typical pattern a threshold
+        let lengths = patterns.iter().map(|pattern| pattern.len()).sorted().collect_vec();
+        let median = lengths.get(lengths.len() / 2).copied().unwrap_or(0);
+        let q = (MIN_Q..=MAX_Q).rev()
+            .find(|q| threshold(median, *q, edit_dist(median, error_rate)) > 0);
+
+        let mut postings: HashMap<u32, Vec<(usize, u32)>> = HashMap::new();
+        let mut thresholds = Vec::new();
+        let mut unfiltered = Vec::new();
+        let Some(q) = q else {
+            return KmerIndex { q, median, error_rate, postings, thresholds, unfiltered };
+        };
+
+        for (i, pattern) in patterns.iter().enumerate() {
+            let mut counts: HashMap<u32, u32> = HashMap::new();
+            let mut degenerate = 0;
+            for qgram in pattern.windows(q) {
+                match encode(qgram) {
+                    Some(code) => *counts.entry(code).or_default() += 1,
+                    None => degenerate += 1,
+                }
+            }
+
+            // q-grams with degenerate bases can't be looked up, so aren't counted on
+            let threshold = threshold(pattern.len(), q, edit_dist(pattern.len(), error_rate)) - degenerate;
+            if pattern.len() < q || threshold <= 0 {
+                unfiltered.push(i);
+            }
+            thresholds.push(threshold);
+
+            for (code, count) in counts {
+                postings.entry(code).or_default().push((i, count));
+            }
+        }
+
+        KmerIndex { q: Some(q), median, error_rate, postings, thresholds, unfiltered }
+    }
+
+    /// If nothing is indexed, the error rate typical patterns would be indexed below
+    pub fn indexed_below(&self) -> Option<f32> {
+        if self.q.is_some() || self.median < MIN_Q {
+            return None;
+        }
+        let edits = (0..=self.median).find(|k| threshold(self.median, MIN_Q, *k) <= 0)?;
+        Some(edits as f32 / self.median as f32)
+    }
+
+    /// Indices of the patterns which could match a sequence, in order,
+    /// or None if they all have to be checked
+    pub fn candidates(&self, seq: &[u8], error_rate: f32) -> Option<Vec<usize>> {
+        // more edits than the thresholds allow for
+        let q = self.q.filter(|_| error_rate <= self.error_rate)?;
+
+        // Ns in the read match anything, so can't be looked up either
+        let codes = seq.windows(q)
+            .map(encode)
+            .collect::<Option<Vec<_>>>()?;
+
+        let mut shared: HashMap<usize, i64> = HashMap::new();
+        for code in codes.into_iter().unique() {
+            for (i, count) in self.postings.get(&code).into_iter().flatten() {
+                *shared.entry(*i).or_default() += *count as i64;
+            }
+        }
+
+        let candidates = shared.into_iter()
+            .filter(|(i, count)| *count >= self.thresholds[*i])
+            .map(|(i, _)| i)
+            .chain(self.unfiltered.iter().copied())
+            .sorted()
+            .dedup()
+            .collect_vec();
+        Some(candidates)
+    }
+}
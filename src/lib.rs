@@ -0,0 +1,1036 @@
+use std::{any::{Any, TypeId}, collections::HashMap, fs::File, io::{BufWriter, Write}, time::Instant};
+
+use bio::stats;
+use clap::{Parser, Subcommand, builder::Str};
+use itertools::Itertools;
+use rayon::iter::{IntoParallelIterator, ParallelIterator, IndexedParallelIterator};
+use reference::{FinalGuides, Ref};
+use seq_io::{fastq::RefRecord, parallel::parallel_fastq};
+
+use crate::{reference::EfficientGuides, find::{StructureResult, RefResult}};
+
+mod abundance;
+mod batch;
+mod cache;
+mod compare;
+mod coverage;
+mod demux;
+mod exact;
+pub mod find;
+mod kmer;
+mod merge;
+mod multiqc;
+mod pools;
+mod profile;
+pub mod reference;
+mod report;
+mod summary;
+pub mod synthetic;
+mod umi;
+
+#[derive(Debug, Parser)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Commands>,
+
+    #[command(flatten)]
+    args: Args,
+}
+
+#[derive(Debug, Subcommand)]
+enum Commands {
+    /// Classify every sample of a sample sheet, building each reference only once
+    Batch(Box<batch::BatchArgs>),
+    /// Combine the guide counts of previous runs into a guide x sample matrix
+    Merge(merge::MergeArgs),
+}
+
+#[derive(Parser,Debug,serde::Serialize)]
+pub struct Args {
+    #[arg(short, long, default_value_t = String::from("stdin"))]
+    input_fastq: String,
+
+    /// Reference of guides; comma-separated if it ends in .csv, otherwise tab-separated.
+    #[arg(short, long, required = true)]
+    reference_tsv: Option<String>,
+
+    /// The reference has a header, and columns are found by name rather than position.
+    /// Without one, only name, spacer, extension and nicking are read, from the first four columns.
+    #[arg(long, default_value_t = false)]
+    reference_header: bool,
+
+    /// Reference columns holding each field, like name=guide_id,spacer=protospacer. Implies --reference-header.
+    #[arg(long)]
+    reference_columns: Option<String>,
+
+    /// Print the stats for reading, rather than as key,value lines.
+    #[arg(long, default_value_t = false)]
+    print_stats: bool,
+
+    /// JSON summary of the run: counts and fractions of each class, the other stats, parameters, reference checksums, and timings.
+    #[arg(long)]
+    summary: Option<String>,
+
+    /// Directory of MultiQC custom content: read classes, guide coverage and chimera rates.
+    #[arg(long)]
+    multiqc: Option<String>,
+
+    /// Self-contained HTML report of read classes, guide coverage, chimeric partners, error profiles and read lengths.
+    #[arg(long)]
+    html_report: Option<String>,
+    
+    #[arg(short, long, required = true)]
+    chimera_fastq: Option<String>,
+
+    #[arg(short, long, required = true)]
+    valid_fastq: Option<String>,
+
+    #[arg(short, long, required = true)]
+    output_tsv: Option<String>,
+
+    #[arg(long, default_value_t = String::from(reference::CYS4))]
+    cys4: String,
+                                           
+    #[arg(short, long, default_value_t = String::from(reference::SCAFFOLD))]
+    // #[arg(short, long, default_value_t = String::from("GTTTCAGAGCTAGAAATAGCAAGTTGAAATAAGGCTAGTCCGTTATCAACTTGAAAAAGTGGCACCGAGTCGGTGC"))]
+    scaffold: String,
+
+    /// Reads have no nicking guide, so end after the extension (PE2-style libraries).
+    #[arg(long, default_value_t = false)]
+    no_nicking: bool,
+
+    /// Linker between the PBS and the 3' motif of epegRNAs, for guides which don't give one.
+    #[arg(long)]
+    linker: Option<String>,
+
+    /// 3' motif of epegRNAs (a sequence, or tevopreq1 / mpknot), for guides which don't give one.
+    #[arg(long)]
+    motif: Option<String>,
+
+    /// Edit distance allowed in the linker and 3' motif, which must run from the end of the PBS to the end of the extension.
+    /// Kept low, so truncated motifs aren't excused as errors.
+    #[arg(long, default_value_t = reference::MOTIF_ERROR_RATE)]
+    motif_error_rate: f32,
+
+    /// Edit distance used for reference sequences.
+    #[arg(short,long, default_value_t = 0.25)]
+    error_rate: f32,
+
+    #[arg(long, default_value_t = false)]
+    careful: bool,
+
+    /// How reads are matched against the guides. Adaptive classifies quickly, then carefully for reads called chimeric, ambiguous or recombined.
+    /// Defaults to careful with --careful, and quick otherwise.
+    #[arg(long, value_enum, conflicts_with = "careful")]
+    mode: Option<find::Mode>,
+
+    /// Directory to classify every read both quickly and carefully into, as a check when tuning parameters:
+    /// the reads they disagree on, with both results and distances, and a confusion table of their classes.
+    #[arg(long)]
+    compare_modes: Option<String>,
+
+    /// Remember the classifications of up to this many distinct reads, so that repeats of them skip classifying.
+    #[arg(long)]
+    cache: Option<usize>,
+
+    /// Also look up every sequence one edit from each guide's, so reads with a single error skip the search too.
+    /// Takes a lot of memory for large libraries. Ignored, with a warning, unless every spacer, extension and nicking
+    /// sgRNA is under 63 nt, without degenerate bases or a separate RTT and PBS.
+    #[arg(long, default_value_t = false)]
+    exact_neighbours: bool,
+
+    #[arg(long, default_value_t = 32)]
+    threads: u32,
+    
+    #[arg(long, default_value_t = 10000)]
+    queue: usize,
+
+    /// Number of valid reads for each guide.
+    #[arg(long)]
+    guide_counts: Option<String>,
+
+    /// Append the reference's extra columns to output rows and guide counts.
+    #[arg(long, default_value_t = false)]
+    annotate: bool,
+
+    /// Observed against expected fraction of valid reads for each guide, with outliers flagged.
+    /// Guides are weighted by the reference's expected column, which every guide must have, or equally without one.
+    #[arg(long)]
+    abundance: Option<String>,
+
+    /// Fold change from the expected fraction beyond which a guide is flagged as enriched or depleted.
+    #[arg(long, default_value_t = 4.0)]
+    outlier_fold: f64,
+
+    /// Per-position error profile of valid reads, aligned against their assigned guides.
+    #[arg(long)]
+    error_profile: Option<String>,
+
+    /// Per-guide error rates of valid reads, for each region.
+    #[arg(long)]
+    guide_error_profile: Option<String>,
+
+    /// Length of a random UMI in the cassette, either fixed (8) or bounded (6-10). Bounded UMIs need --umi-flank.
+    #[arg(long)]
+    umi_length: Option<String>,
+
+    /// Where the UMI sits in the cassette.
+    #[arg(long, value_enum, default_value_t = umi::UmiLocation::BeforeNicking)]
+    umi_location: umi::UmiLocation,
+
+    /// Sequence directly after the UMI, which ends it.
+    #[arg(long)]
+    umi_flank: Option<String>,
+
+    /// Name of the UMI, tagged onto the description of valid and chimera reads.
+    #[arg(long, default_value_t = String::from("UMI"))]
+    umi_name: String,
+
+    /// Collapse reads by (UMI, guide or chimera signature), and count the distinct molecules as well as reads. Needs --umi-length.
+    #[arg(long, default_value_t = false, requires = "umi_length")]
+    dedup: bool,
+
+    /// Number of chimeric reads for each signature of parent guides, and with --dedup, their molecules and PCR chimeras.
+    #[arg(long)]
+    chimera_counts: Option<String>,
+
+    /// Number of chimeric reads for each pair of guides which contributed regions to them,
+    /// and with a pool column in the reference, whether both guides are from the same pool.
+    #[arg(long)]
+    partners: Option<String>,
+
+    /// Valid reads and chimera rates of each pool in the reference's pool column, with chimeras
+    /// split into those within the pool, those with another pool, and those whose only identified guide is in the pool.
+    #[arg(long)]
+    pool_stats: Option<String>,
+
+    /// Demultiplex reads by the index at the end of their header, from a tab-separated sheet of sample and index.
+    /// Every output is written once per sample, with the sample name before its extension.
+    #[arg(long)]
+    sample_sheet: Option<String>,
+
+    /// Name of the sample of a run without a sample sheet, in the count matrix, chimera rates and MultiQC content.
+    /// Defaults to sample, and in MultiQC content to the input's file name.
+    #[arg(long, conflicts_with = "sample_sheet")]
+    sample_name: Option<String>,
+
+    /// Mismatches allowed between a read's index and the sample sheet.
+    #[arg(long, default_value_t = 1)]
+    index_mismatches: usize,
+
+    /// Guide x sample matrix of valid reads, across demultiplexed or batch samples.
+    #[arg(long)]
+    count_matrix: Option<String>,
+
+    /// Chimeric fraction of well-structured reads, for each demultiplexed or batch sample.
+    #[arg(long)]
+    chimera_rates: Option<String>,
+
+}
+
+pub fn _seq_to_string(seq: &[u8]) -> String {
+    String::from_utf8(seq.to_vec()).expect("Bad seq!")
+}
+
+mod input {
+    use std::{io::{BufRead, BufReader, stdin}, path::Path, fs::File, ffi::OsStr};
+
+    use crate::Args;
+
+    /// Checks the arguments, and either opens a file or reads from stdin
+    pub fn reader(args: &Args) -> Box<dyn BufRead> {
+        path_reader(&args.input_fastq)
+    }
+
+    /// Either opens a file or reads from stdin
+    pub fn path_reader(path: &str) -> Box<dyn BufRead> {
+        if path.eq("stdin") {
+            // just read straight from stdin
+            Box::new(BufReader::new(stdin()))
+        } else {
+            let path = Path::new(path);
+            let file = match File::open(path) {
+                Ok(file) => file,
+                Err(_) => panic!("Couldn't open {}!", path.display()),
+            };
+
+            if path.extension() == Some(OsStr::new("gz")) {
+                Box::new(BufReader::new(
+                    flate2::read::MultiGzDecoder::new(file)))
+            } else {
+                Box::new(BufReader::new(file))
+            }
+        }
+    }
+}
+
+mod output {
+    use std::ffi::OsStr;
+    use std::fs::File;
+    use std::io::{Write, BufWriter, stdout};
+    use std::path::Path;
+    use crate::find::StructureResult;
+
+    pub fn print_header<T: Write>(output: &mut T) {
+        writeln!(output, "id\tsequence\tcdr3_sequence")
+        .expect("Couldn't write header line to output!");
+    }
+    
+    /// Writes the result of one read, followed by any extra columns
+    pub fn print_one<T: Write>(output: &mut T, output_record: (&str, &StructureResult), extras: &[&str]) {
+        write!(output, "{}\t{:?}", output_record.0, output_record.1)
+            .expect("Couldn't write line to output!");
+        for extra in extras {
+            write!(output, "\t{}", extra)
+                .expect("Couldn't write line to output!");
+        }
+        writeln!(output)
+            .expect("Couldn't write line to output!");
+    }
+
+    /// Either opens a file or writes to stdout
+    pub fn path_writer(path: &str) -> Box<dyn Write> {
+        if path.eq("stdout") {
+            // just read straight from stdin
+            Box::new(BufWriter::new(stdout()))
+        } else {
+            let path = Path::new(path);
+            let file = match File::create(path) {
+                Ok(file) => file,
+                Err(_) => panic!("Couldn't open {}!", path.display()),
+            };
+
+            if path.extension() == Some(OsStr::new("gz")) {
+                Box::new(BufWriter::new(
+                    flate2::write::GzEncoder::new(file, flate2::Compression::default())))
+            } else {
+                Box::new(BufWriter::new(file))
+            }
+        }
+    }
+}
+
+/// Runs whichever command the arguments ask for
+pub fn cli_main() {
+    // seq_io_main();
+    let cli = Cli::parse();
+    match &cli.command {
+        Some(Commands::Batch(batch_args)) => batch::batch_main(batch_args),
+        Some(Commands::Merge(merge_args)) => merge::merge_main(merge_args),
+        None => bio_main(&cli.args),
+    }
+}
+
+fn bio_main(args: &Args) {
+    // println!("Started..");
+    let started = Instant::now();
+    let mut summary = summary::Summary::new(args);
+
+    let reference_tsv = args.reference_tsv.as_ref().expect("Missing reference!");
+    let classifier = Classifier::new(args, reference_tsv);
+    summary.add_reference(reference_tsv, classifier.reference.guides.len(), started.elapsed());
+    let sample_sheet = args.sample_sheet.as_ref()
+        .map(|sample_sheet| demux::SampleSheet::new(sample_sheet, args.index_mismatches));
+
+    // println!("Parsed reference..");
+
+    // each sample gets its own outputs, and reads matching no sample are kept together
+    let paths = OutputPaths::new(args);
+    let pooled = !classifier.reference.pools.is_empty();
+    let adaptive = classifier.mode == find::Mode::Adaptive;
+    let mut samples = match &sample_sheet {
+        Some(sample_sheet) => sample_sheet.samples.iter()
+            .map(|sample| &sample[..])
+            .chain([demux::UNDETERMINED])
+            .map(|sample| (Some(sample), SampleOutputs::new(paths.for_sample(sample), pooled, adaptive)))
+            .collect_vec(),
+        None => vec![(None, SampleOutputs::new(paths, pooled, adaptive))],
+    };
+
+    let cache = args.cache.map(cache::Cache::new);
+    let classifying = Instant::now();
+    classifier.run(input::reader(args), sample_sheet.as_ref(), &mut samples, cache.as_ref());
+    summary.add_classification(classifying.elapsed());
+    if let Some(cache) = &cache {
+        summary.add_cache(cache.summary());
+    }
+
+    let mut matrix = merge::CountMatrix::new();
+    let mut rates = Vec::new();
+    let mut multiqc_samples = Vec::new();
+    for (sample, outputs) in samples {
+        let out_stats = outputs.finish(sample, &classifier.reference, args);
+        out_stats.report(sample, args);
+        let valid_counts = out_stats.valid_counts(&classifier.reference);
+        if args.summary.is_some() {
+            summary.add_sample(sample, &out_stats.stats(args.dedup), &out_stats.classes(), &valid_counts);
+        }
+
+        let sample = sample.or(args.sample_name.as_deref());
+        matrix.add_sample(sample.unwrap_or("sample"), valid_counts.iter().cloned());
+        rates.push(out_stats.chimera_rate(sample.unwrap_or("sample")));
+        multiqc_samples.push(multiqc::MultiqcSample {
+            name: sample.map_or_else(|| multiqc::sample_name(&args.input_fastq), String::from),
+            stats: out_stats.stats(args.dedup),
+            classes: out_stats.classes(),
+            valid_counts,
+        });
+    }
+
+    if let Some(path) = &args.count_matrix {
+        matrix.write(&mut output::path_writer(path));
+    }
+    if let Some(path) = &args.chimera_rates {
+        merge::write_chimera_rates(&mut output::path_writer(path), &rates);
+    }
+    if let Some(path) = &args.multiqc {
+        multiqc::write_multiqc(path, &multiqc_samples);
+    }
+    if let Some(path) = &args.summary {
+        summary.write(&mut output::path_writer(path), started.elapsed());
+    }
+}
+
+/// A reference, and everything built from it to classify reads against it
+struct Classifier<'a> {
+    args: &'a Args,
+    reference: Ref,
+    efficient_guides: EfficientGuides,
+    final_guides: FinalGuides,
+    mode: find::Mode,
+    umi: Option<umi::Umi>,
+}
+
+impl<'a> Classifier<'a> {
+    fn new(args: &'a Args, reference_tsv: &str) -> Self {
+        let reference = reference::Ref::new(args, reference_tsv);
+        let efficient_guides = EfficientGuides::new(&reference.guides, args.error_rate);
+        let final_guides = FinalGuides::new(&reference.guides, args.error_rate, args.exact_neighbours);
+        if let Some(indexed_below) = final_guides.spacers_indexed_below() {
+            eprintln!("Warning: the spacers are too short to index at --error-rate {}, so every read is checked against every guide. Error rates below {} are indexed!",
+                args.error_rate, indexed_below);
+        }
+        // println!("Produced efficient reference..");
+        if args.abundance.is_some() {
+            // check the weights before classifying, rather than once every read is done
+            abundance::weights(&reference);
+        }
+
+        let mode = args.mode.unwrap_or(if args.careful { find::Mode::Careful } else { find::Mode::Quick });
+
+        Classifier { args, reference, efficient_guides, final_guides, mode, umi: umi::Umi::new(args) }
+    }
+
+    /// Classifies one read, and works out which sample it belongs to
+    fn classify_read<'c>(
+        &'c self,
+        record: &bio::io::fastq::Record,
+        sample_sheet: Option<&demux::SampleSheet>,
+        cache: Option<&cache::Cache<(StructureResult<'c>, find::Mode)>>
+    ) -> (usize, ClassifiedRead<'c>) {
+        let args = self.args;
+        let profiling = args.error_profile.is_some() || args.guide_error_profile.is_some() || args.html_report.is_some();
+        let parenting = args.chimera_counts.is_some() || args.dedup || args.partners.is_some() || args.html_report.is_some()
+            || !self.reference.pools.is_empty();
+
+        let sample = match sample_sheet {
+            Some(sample_sheet) => sample_sheet.assign(record.desc())
+                .unwrap_or(sample_sheet.samples.len()),
+            None => 0,
+        };
+        let regions = find::break_into_regions(record.seq(), &self.reference, args.error_rate);
+        let classify = || match self.mode {
+            find::Mode::Quick =>
+                (find::structure_classify_quickly(regions, &self.reference, &self.final_guides, args.error_rate), find::Mode::Quick),
+            find::Mode::Careful =>
+                (find::structure_classify_carefully(regions, &self.reference, &self.efficient_guides, args.error_rate), find::Mode::Careful),
+            find::Mode::Adaptive =>
+                find::structure_classify_adaptively(regions, &self.reference, &self.efficient_guides, &self.final_guides, args.error_rate),
+        };
+        let (out, decided_by) = match (cache, &regions) {
+            (Some(cache), Some(regions)) => cache.classify(regions, classify),
+            _ => classify(),
+        };
+        let comparison = args.compare_modes.is_some().then(|| {
+            // whichever the mode already worked out needn't be again
+            let quick = match decided_by {
+                find::Mode::Quick => out.clone(),
+                _ => find::structure_classify_quickly(regions, &self.reference, &self.final_guides, args.error_rate),
+            };
+            let careful = match decided_by {
+                find::Mode::Careful => out.clone(),
+                _ => find::structure_classify_carefully(regions, &self.reference, &self.efficient_guides, args.error_rate),
+            };
+            (quick, careful)
+        });
+        let errors = match (&out, regions) {
+            (StructureResult::WellStructured(RefResult::Valid(name, _)), Some(regions)) if profiling =>
+                Some(profile::read_errors(&regions, name, &self.final_guides)),
+            _ => None,
+        };
+        let read_umi = match (&self.umi, regions) {
+            (Some(umi), Some(regions)) => umi.extract(&regions, args.error_rate)
+                .map(|read_umi| String::from_utf8(read_umi.to_vec()).expect("Bad UMI!")),
+            _ => None,
+        };
+        // tag the read id with its UMI like umi_tools does, and name it in the description
+        let record_string = match (&self.umi, &read_umi) {
+            (Some(umi), Some(read_umi)) => {
+                let desc = match record.desc() {
+                    Some(desc) => format!("{} {}={}", desc, umi.name, read_umi),
+                    None => format!("{}={}", umi.name, read_umi),
+                };
+                bio::io::fastq::Record::with_attrs(
+                    &format!("{}_{}", record.id(), read_umi), Some(&desc), record.seq(), record.qual()
+                ).to_string()
+            },
+            _ => record.to_string(),
+        };
+        let parents = match (&out, regions) {
+            (StructureResult::WellStructured(RefResult::Chimera), Some(regions)) if parenting =>
+                Some(find::chimera_parents(&regions, &self.efficient_guides, args.error_rate)),
+            _ => None,
+        };
+
+        (sample, ClassifiedRead { record_string, id: record.id().to_owned(), length: record.seq().len(), out, decided_by, comparison, errors, read_umi, parents })
+    }
+
+    /// Classifies every read of an input, and adds each to the outputs of its sample
+    fn run<'c>(
+        &'c self,
+        input: Box<dyn std::io::BufRead>,
+        sample_sheet: Option<&demux::SampleSheet>,
+        samples: &mut [(Option<&str>, SampleOutputs)],
+        cache: Option<&cache::Cache<(StructureResult<'c>, find::Mode)>>
+    ) {
+        let records = bio::io::fastq::Reader::new(input)
+            .records();
+
+        for chunk in &records.chunks(100000) {
+            let mut temp = Vec::new();
+            chunk.collect_vec().into_par_iter()
+                .map(|result| {
+                    match result {
+                        Ok(record) => self.classify_read(&record, sample_sheet, cache),
+                        Err(_) => panic!("Bad record!"),
+                    }
+                }
+            ).collect_into_vec(&mut temp);
+
+            for (sample, read) in temp {
+                samples[sample].1.add(read, &self.reference, self.args.annotate, self.umi.is_some());
+            }
+        }
+    }
+}
+
+/// Where the outputs of a run, or of one sample in it, are written
+struct OutputPaths {
+    output_tsv: String,
+    valid_fastq: String,
+    chimera_fastq: String,
+    guide_counts: Option<String>,
+    abundance: Option<String>,
+    chimera_counts: Option<String>,
+    error_profile: Option<String>,
+    guide_error_profile: Option<String>,
+    partners: Option<String>,
+    pool_stats: Option<String>,
+    html_report: Option<String>,
+    compare_modes: Option<String>,
+}
+
+impl OutputPaths {
+    fn new(args: &Args) -> Self {
+        OutputPaths {
+            output_tsv: args.output_tsv.clone().expect("Missing output!"),
+            valid_fastq: args.valid_fastq.clone().expect("Missing valid fastq!"),
+            chimera_fastq: args.chimera_fastq.clone().expect("Missing chimera fastq!"),
+            guide_counts: args.guide_counts.clone(),
+            abundance: args.abundance.clone(),
+            chimera_counts: args.chimera_counts.clone(),
+            error_profile: args.error_profile.clone(),
+            guide_error_profile: args.guide_error_profile.clone(),
+            partners: args.partners.clone(),
+            pool_stats: args.pool_stats.clone(),
+            html_report: args.html_report.clone(),
+            compare_modes: args.compare_modes.clone(),
+        }
+    }
+
+    /// The same outputs, with the sample name in each path
+    fn for_sample(&self, sample: &str) -> Self {
+        let path = |path: &String| demux::sample_path(path, sample);
+        OutputPaths {
+            output_tsv: path(&self.output_tsv),
+            valid_fastq: path(&self.valid_fastq),
+            chimera_fastq: path(&self.chimera_fastq),
+            guide_counts: self.guide_counts.as_ref().map(path),
+            abundance: self.abundance.as_ref().map(path),
+            chimera_counts: self.chimera_counts.as_ref().map(path),
+            error_profile: self.error_profile.as_ref().map(path),
+            guide_error_profile: self.guide_error_profile.as_ref().map(path),
+            partners: self.partners.as_ref().map(path),
+            pool_stats: self.pool_stats.as_ref().map(path),
+            html_report: self.html_report.as_ref().map(path),
+            compare_modes: self.compare_modes.as_ref().map(path),
+        }
+    }
+}
+
+/// Everything worked out about one read, ready to be written down
+struct ClassifiedRead<'a> {
+    record_string: String,
+    id: String,
+    length: usize,
+    out: StructureResult<'a>,
+    // the mode whose classification was kept
+    decided_by: find::Mode,
+    // the quick and careful classifications, when comparing them
+    comparison: Option<(StructureResult<'a>, StructureResult<'a>)>,
+    errors: Option<profile::ReadErrors<'a>>,
+    read_umi: Option<String>,
+    parents: Option<find::ChimeraParents>,
+}
+
+/// The open outputs and running stats of one sample
+struct SampleOutputs {
+    writer: Box<dyn Write>,
+    valid_fastq: BufWriter<File>,
+    chimera_fastq: BufWriter<File>,
+    out_stats: OutStats,
+    error_profile: profile::ErrorProfile,
+    mode_comparison: Option<compare::ModeComparison>,
+    paths: OutputPaths,
+}
+
+impl SampleOutputs {
+    fn new(paths: OutputPaths, pooled: bool, adaptive: bool) -> Self {
+        SampleOutputs {
+            writer: output::path_writer(&paths.output_tsv),
+            valid_fastq: BufWriter::new(
+                File::create(&paths.valid_fastq).unwrap()),
+            chimera_fastq: BufWriter::new(
+                File::create(&paths.chimera_fastq).unwrap()),
+            out_stats: OutStats::new(pooled, adaptive),
+            error_profile: profile::ErrorProfile::new(),
+            mode_comparison: paths.compare_modes.as_deref().map(compare::ModeComparison::new),
+            paths,
+        }
+    }
+
+    fn add(&mut self, read: ClassifiedRead, reference: &Ref, annotate: bool, with_umi: bool) {
+        let ClassifiedRead { record_string, id, length, out, decided_by, comparison, errors, read_umi, parents } = read;
+
+        self.out_stats.add(&out);
+        self.out_stats.add_decided_by(decided_by);
+        self.out_stats.add_length(&out, length);
+        if let Some(errors) = errors {
+            self.error_profile.add(&errors);
+        }
+        if let (Some(mode_comparison), Some((quick, careful))) = (&mut self.mode_comparison, &comparison) {
+            mode_comparison.add(&id, quick, careful);
+        }
+        if let StructureResult::WellStructured(RefResult::Valid(name, _)) = &out {
+            if with_umi {
+                self.out_stats.guide_umis.add(name, read_umi.as_deref().map(str::as_bytes));
+            }
+        }
+        if let Some(parents) = parents {
+            self.out_stats.add_pools(&parents, reference);
+            self.out_stats.chimera_counts.add(parents, read_umi.as_deref().map(str::as_bytes));
+        }
+
+        let mut extras = Vec::new();
+        if annotate {
+            extras.extend(reference.annotations(out.guide()));
+        }
+        if with_umi {
+            extras.push(read_umi.as_deref().unwrap_or(""));
+        }
+        if self.out_stats.escalated.is_some() {
+            extras.push(decided_by.name());
+        }
+        output::print_one(&mut self.writer, (&id, &out), &extras);
+
+        if let StructureResult::WellStructured(w) = out {
+            match w {
+                // write down the valid ones
+                RefResult::Valid(_, _) => {
+                    Result::unwrap(self.valid_fastq.write(record_string[..].as_bytes())); 
+                },
+                // write down the chimeras
+                RefResult::Chimera | RefResult::RecombinedRtt(_, _) => { 
+                    Result::unwrap(self.chimera_fastq.write(record_string[..].as_bytes()));
+                },
+                _ => {}
+            }
+        }
+    }
+
+    /// Writes the per-guide tables and the report, and hands back the stats
+    fn finish(self, sample: Option<&str>, reference: &Ref, args: &Args) -> OutStats {
+        let paths = &self.paths;
+        if let Some(path) = &paths.guide_counts {
+            self.out_stats.write_guide_counts(&mut output::path_writer(path), reference, args.annotate, args.dedup);
+        }
+        if let Some(path) = &paths.abundance {
+            abundance::write_abundance(&mut output::path_writer(path), reference, &self.out_stats.valid_counts(reference), args.outlier_fold);
+        }
+        if let Some(path) = &paths.chimera_counts {
+            self.out_stats.chimera_counts.write(&mut output::path_writer(path), &self.out_stats.guide_umis, args.dedup);
+        }
+        if let Some(path) = &paths.partners {
+            self.out_stats.chimera_counts.write_partners(&mut output::path_writer(path), reference);
+        }
+        if let Some(path) = &paths.pool_stats {
+            pools::write_pool_stats(&mut output::path_writer(path), reference,
+                &self.out_stats.valid_counts(reference), &self.out_stats.chimera_counts);
+        }
+        if let Some(mode_comparison) = self.mode_comparison {
+            mode_comparison.finish();
+        }
+        if let Some(path) = &paths.error_profile {
+            self.error_profile.write_positions(&mut output::path_writer(path));
+        }
+        if let Some(path) = &paths.guide_error_profile {
+            self.error_profile.write_guides(&mut output::path_writer(path));
+        }
+        if let Some(path) = &paths.html_report {
+            report::write_report(&mut output::path_writer(path), &report::ReportData {
+                sample,
+                classes: self.out_stats.classes(),
+                valid_counts: self.out_stats.valid_counts(reference),
+                partner_pairs: self.out_stats.chimera_counts.partner_pairs(),
+                error_profile: &self.error_profile,
+                lengths: &self.out_stats.lengths,
+            });
+        }
+
+        self.out_stats
+    }
+}
+
+
+struct OutStats {
+    total: u32,
+    well_structured: u32,
+    chimeric: u32,
+    valid: u32,
+    ambiguous: u32,
+    recombined_rtt: u32,
+    motif_truncated: u32,
+    // whether the reference has pools, and chimeras are split by them
+    pooled: bool,
+    intra_pool_chimeric: u32,
+    inter_pool_chimeric: u32,
+    // chimeras with fewer than two guides identified, so no partner pool to compare
+    unknown_pool_chimeric: u32,
+    // reads escalated to careful classification, in adaptive mode
+    escalated: Option<u32>,
+    guide_counts: HashMap<String, u32>,
+    // how many reads of each class had each length
+    lengths: HashMap<&'static str, HashMap<usize, u32>>,
+    // distinct UMIs of the valid reads for each guide
+    guide_umis: umi::UmiCounts,
+    chimera_counts: umi::ChimeraCounts,
+}
+
+impl OutStats {
+    fn new(pooled: bool, adaptive: bool) -> Self {
+        OutStats {
+            total: 0, 
+            well_structured: 0, 
+            chimeric: 0, 
+            valid: 0, 
+            ambiguous: 0,
+            recombined_rtt: 0,
+            motif_truncated: 0,
+            pooled,
+            intra_pool_chimeric: 0,
+            inter_pool_chimeric: 0,
+            unknown_pool_chimeric: 0,
+            escalated: adaptive.then_some(0),
+            guide_counts: HashMap::new(),
+            lengths: HashMap::new(),
+            guide_umis: umi::UmiCounts::default(),
+            chimera_counts: umi::ChimeraCounts::default(),
+        }
+    }
+
+    /// Counts a chimera as within one pool or between pools, by the guides it came from.
+    /// Chimeras with fewer than two guides identified are counted as unknown, so the three add up to the chimeras
+    fn add_pools(&mut self, parents: &find::ChimeraParents, reference: &Ref) {
+        if !self.pooled {
+            return;
+        }
+
+        let guides = parents.guides();
+        if guides.len() < 2 {
+            self.unknown_pool_chimeric += 1;
+        } else if reference.same_pool(&guides) {
+            self.intra_pool_chimeric += 1;
+        } else {
+            self.inter_pool_chimeric += 1;
+        }
+    }
+
+    /// Counts the reads careful classification decided, when quick classification decides the rest
+    fn add_decided_by(&mut self, decided_by: find::Mode) {
+        if let (Some(escalated), find::Mode::Careful) = (&mut self.escalated, decided_by) {
+            *escalated += 1;
+        }
+    }
+
+    fn add_length(&mut self, result: &StructureResult, length: usize) {
+        *self.lengths.entry(result.class()).or_default().entry(length).or_default() += 1;
+    }
+
+    fn add(&mut self, result: &StructureResult) {
+        self.total += 1;
+        match result {
+            StructureResult::WellStructured(w) => {
+                self.well_structured += 1;
+                match w {
+                    RefResult::Valid(name, _) => { 
+                        self.valid += 1;
+                        match self.guide_counts.get_mut(*name) {
+                            Some(count) => *count += 1,
+                            None => { self.guide_counts.insert(name.to_string(), 1); },
+                        }
+                    },
+                    RefResult::Chimera => { self.chimeric += 1 },
+                    RefResult::Ambiguous => { self.ambiguous += 1 },
+                    RefResult::RecombinedRtt(_, _) => { self.recombined_rtt += 1 },
+                }
+            },
+            StructureResult::MotifTruncated(_) => { self.motif_truncated += 1 },
+            StructureResult::BadlyStructured => { },
+        }
+    }
+
+    fn print_stats(&self) {
+        println!("well-structured (scaffold - cys4 - scaffold): {} / {} = {}%", 
+            self.well_structured, self.total, (self.well_structured as f32) / (self.total as f32) * 100.0);
+        println!("valid (spacer, extension, nicking): {} / {} = {}% ({}% of well-structured reads)", 
+            self.valid, self.total, (self.valid as f32) / (self.total as f32) * 100.0, (self.valid as f32) / (self.well_structured as f32) * 100.0);
+        println!("chimeric (spacer, extension, nicking): {} / {} = {}% ({}% of well-structured reads)", 
+                self.chimeric, self.total, (self.chimeric as f32) / (self.total as f32) * 100.0, (self.chimeric as f32) / (self.well_structured as f32) * 100.0);
+        println!("ambiguous (spacer, extension, nicking): {} / {} = {}% ({}% of well-structured reads)", 
+            self.ambiguous, self.total, (self.ambiguous as f32) / (self.total as f32) * 100.0, (self.ambiguous as f32) / (self.well_structured as f32) * 100.0);
+        println!("recombined RTT (spacer, PBS, nicking): {} / {} = {}% ({}% of well-structured reads)", 
+            self.recombined_rtt, self.total, (self.recombined_rtt as f32) / (self.total as f32) * 100.0, (self.recombined_rtt as f32) / (self.well_structured as f32) * 100.0);
+        println!("motif-truncated (linker, 3' motif): {} / {} = {}%", 
+            self.motif_truncated, self.total, (self.motif_truncated as f32) / (self.total as f32) * 100.0);
+        if let Some(escalated) = self.escalated {
+            println!("escalated (classified carefully after quickly): {} / {} = {}%",
+                escalated, self.total, (escalated as f32) / (self.total as f32) * 100.0);
+        }
+    }
+
+    /// The valid count of every guide in the reference, including those with none
+    fn valid_counts(&self, reference: &Ref) -> Vec<(String, u32)> {
+        reference.guides.iter()
+            .map(|guide| (guide.name.clone(), *self.guide_counts.get(&guide.name).unwrap_or(&0)))
+            .collect_vec()
+    }
+
+    /// The (sample, well-structured, valid, chimeric) row of the chimera rates
+    fn chimera_rate(&self, sample: &str) -> (String, usize, usize, usize) {
+        (sample.to_string(), self.well_structured as usize, self.valid as usize, self.chimeric as usize)
+    }
+
+    /// Writes the valid count of every guide in the reference, including those with none
+    fn write_guide_counts<T: Write>(&self, output: &mut T, reference: &Ref, annotate: bool, dedup: bool) {
+        let mut header = vec!["guide", "valid"];
+        if dedup {
+            header.push("deduplicated");
+        }
+        if annotate {
+            header.extend(reference.annotation_columns.iter().map(|c| &c[..]));
+        }
+        writeln!(output, "{}", header.join("\t"))
+            .expect("Couldn't write header line to guide counts!");
+
+        for guide in &reference.guides {
+            let count = self.guide_counts.get(&guide.name).unwrap_or(&0);
+            let mut row = vec![guide.name.clone(), count.to_string()];
+            if dedup {
+                row.push(self.guide_umis.get(&guide.name).to_string());
+            }
+            if annotate {
+                row.extend(guide.annotations.iter().cloned());
+            }
+            writeln!(output, "{}", row.join("\t"))
+                .expect("Couldn't write line to guide counts!");
+        }
+    }
+
+    /// The number of reads in each class, in the order of `find::CLASSES`
+    fn classes(&self) -> Vec<(&'static str, usize)> {
+        let counts = [
+            self.valid,
+            self.chimeric,
+            self.recombined_rtt,
+            self.ambiguous,
+            self.motif_truncated,
+            // reads which are neither well-structured nor merely motif-truncated
+            self.total - self.well_structured - self.motif_truncated,
+        ];
+
+        find::CLASSES.iter().zip(counts)
+            .map(|((class, _), count)| (*class, count as usize))
+            .collect_vec()
+    }
+
+    /// The name and value of each stat, in the order they're reported
+    fn stats(&self, dedup: bool) -> Vec<(&'static str, usize)> {
+        let mut stats = vec![
+            ("reads", self.total as usize),
+            ("wellstructured", self.well_structured as usize),
+            ("valid", self.valid as usize),
+            ("chimeric", self.chimeric as usize),
+            ("ambiguous", self.ambiguous as usize),
+            ("recombinedrtt", self.recombined_rtt as usize),
+            ("motiftruncated", self.motif_truncated as usize),
+        ];
+        if self.pooled {
+            stats.extend([
+                ("intrapoolchimeric", self.intra_pool_chimeric as usize),
+                ("interpoolchimeric", self.inter_pool_chimeric as usize),
+                ("unknownpoolchimeric", self.unknown_pool_chimeric as usize),
+            ]);
+        }
+        if let Some(escalated) = self.escalated {
+            stats.push(("escalated", escalated as usize));
+        }
+        if dedup {
+            stats.extend([
+                ("dedupvalid", self.guide_umis.total()),
+                // reads too short for their UMI, or missing its flank, which aren't deduplicated
+                ("noumivalid", self.guide_umis.missing()),
+                ("dedupchimeric", self.chimera_counts.deduplicated()),
+                ("noumichimeric", self.chimera_counts.missing()),
+                ("pcrchimeric", self.chimera_counts.pcr_total(&self.guide_umis)),
+            ]);
+        }
+        stats
+    }
+
+    /// Prints the stats in the format asked for, under the sample name when demultiplexing
+    fn report(&self, sample: Option<&str>, args: &Args) {
+        if args.print_stats {
+            if let Some(sample) = sample {
+                println!("{}:", sample);
+            }
+            self.print_stats();
+        } else {
+            self.print_stats_csv(sample, args.dedup);
+        }
+    }
+
+    /// Prints one stat per line, each starting with the sample name when demultiplexing
+    fn print_stats_csv(&self, sample: Option<&str>, dedup: bool) {
+        let prefix = sample.map(|sample| format!("{},", sample)).unwrap_or_default();
+        for (name, value) in self.stats(dedup) {
+            println!("{}{},{}", prefix, name, value);
+        }
+    }
+}
+
+fn seq_io_main() {
+    use seq_io::fastq::{Reader,Record};
+
+    let args = Cli::parse().args;
+    let reference = reference::Ref::new(&args, args.reference_tsv.as_ref().unwrap());
+
+    let reader = Reader::from_path(&args.input_fastq).unwrap();
+
+    let mut writer = output::path_writer(args.output_tsv.as_ref().unwrap());
+    let mut valid_fastq = BufWriter::new(File::create(args.valid_fastq.unwrap()).unwrap());
+    let mut chimera_fastq = BufWriter::new(File::create(args.chimera_fastq.unwrap()).unwrap());
+
+    let efficient_guides = EfficientGuides::new(&reference.guides, args.error_rate);
+    let final_guides = FinalGuides::new(&reference.guides, args.error_rate, args.exact_neighbours);
+
+
+    let classify = |seq: &[u8]| -> StructureResult {
+        if args.careful {
+            find::structure_classify_carefully(find::break_into_regions(seq, &reference, args.error_rate), &reference, &efficient_guides, args.error_rate)
+        } else {
+            find::structure_classify_quickly(find::break_into_regions(seq, &reference, args.error_rate), &reference, &final_guides, args.error_rate)
+        }
+    };
+
+    let f = |record: RefRecord<'_>, out: &mut StructureResult| {
+
+    };
+
+    // let mut out_vec = Vec::new();
+
+    let mut out_stats = OutStats::new(false, false);
+    let _ = parallel_fastq(reader, args.threads, args.queue, |record, out| {
+        *out = classify(record.seq());
+    }, |record, out| {
+        if let StructureResult::WellStructured(w) = out {
+            match w {
+                // write down the valid ones
+                RefResult::Valid(_, _) => {
+                    record.write(&mut valid_fastq).unwrap(); 
+                },
+                // write down the chimeras
+                RefResult::Chimera => { 
+                    record.write(&mut chimera_fastq).unwrap(); 
+                },
+                _ => {}
+            }
+        }
+
+        output::print_one(&mut writer, (record.id().unwrap(), out), &[]);
+
+        // keep them all in a big vec
+        out_stats.add(out);
+        // out_vec.push(out.clone());
+
+        None::<()>
+    });
+
+    out_stats.print_stats()
+    // print_stats(&out_vec);
+}
+
+fn print_stats(out: &[StructureResult]) {
+    let total = out.len();
+    
+    let well_structured = out.iter().filter(|r| {
+        matches!(r, StructureResult::WellStructured(_))
+    }).collect_vec().len();
+
+    let valid_reads_map = out.iter().counts();
+    let zipped = valid_reads_map.keys().zip(valid_reads_map.values()).sorted_by_key(|(_, b)| **b);
+    for (k, v) in zipped {
+        println!("{:?}\t{}", k, v);
+    }
+
+    let valid = out.iter().filter(|r| {
+        matches!(r, StructureResult::WellStructured(RefResult::Valid(_, _)))
+    }).collect_vec().len();
+
+    let chimeric = out.iter().filter(|r| {
+        matches!(r, StructureResult::WellStructured(RefResult::Chimera))
+    }).collect_vec().len();
+
+    let ambiguous = out.iter().filter(|r| {
+        matches!(r, StructureResult::WellStructured(RefResult::Ambiguous))
+    }).collect_vec().len();
+
+    println!("well-structured (scaffold - cys4 - scaffold): {} / {} = {}%", 
+        well_structured, total, (well_structured as f32) / (total as f32) * 100.0);
+    println!("valid (spacer, extension, nicking): {} / {} = {}% ({}% of well-structured reads)", 
+        valid, total, (valid as f32) / (total as f32) * 100.0, (valid as f32) / (well_structured as f32) * 100.0);
+    println!("chimeric (spacer, extension, nicking): {} / {} = {}% ({}% of well-structured reads)", 
+            chimeric, total, (chimeric as f32) / (total as f32) * 100.0, (chimeric as f32) / (well_structured as f32) * 100.0);
+    println!("ambiguous (spacer, extension, nicking): {} / {} = {}% ({}% of well-structured reads)", 
+        ambiguous, total, (ambiguous as f32) / (total as f32) * 100.0, (ambiguous as f32) / (well_structured as f32) * 100.0);
+}
\ No newline at end of file
@@ -0,0 +1,176 @@
+use std::{collections::HashMap, fs, io::Write, path::Path};
+
+use itertools::Itertools;
+
+use crate::{input, output};
+
+/// Counts of each guide across samples, with guides in the order they were first seen
+#[derive(Default)]
+pub struct CountMatrix {
+    guides: Vec<String>,
+    guide_index: HashMap<String, usize>,
+    samples: Vec<String>,
+    // for each sample, the count of each guide by its index
+    counts: Vec<Vec<u32>>,
+}
+
+impl CountMatrix {
+    pub fn new() -> Self {
+        CountMatrix::default()
+    }
+
+    pub fn add_sample(&mut self, sample: &str, counts: impl IntoIterator<Item = (String, u32)>) {
+        if self.samples.iter().any(|s| s == sample) {
+            panic!("Sample {} is in the count matrix twice!", sample);
+        }
+
+        let mut sample_counts = vec![0; self.guides.len()];
+        for (guide, count) in counts {
+            let i = match self.guide_index.get(&guide) {
+                Some(i) => *i,
+                None => {
+                    self.guides.push(guide.clone());
+                    self.guide_index.insert(guide, self.guides.len() - 1);
+                    self.guides.len() - 1
+                },
+            };
+            if sample_counts.len() <= i {
+                sample_counts.resize(i + 1, 0);
+            }
+            sample_counts[i] += count;
+        }
+
+        self.samples.push(sample.to_string());
+        self.counts.push(sample_counts);
+    }
+
+    /// Writes one row per guide and one column per sample
+    pub fn write<T: Write>(&self, output: &mut T) {
+        writeln!(output, "guide\t{}", self.samples.join("\t"))
+            .expect("Couldn't write header line to count matrix!");
+
+        for (i, guide) in self.guides.iter().enumerate() {
+            let row = self.counts.iter()
+                .map(|counts| counts.get(i).unwrap_or(&0))
+                .join("\t");
+            writeln!(output, "{}\t{}", guide, row)
+                .expect("Couldn't write line to count matrix!");
+        }
+    }
+}
+
+/// Writes the chimeric fraction of well-structured reads, for each sample.
+/// Each row of stats is (sample, well-structured, valid, chimeric)
+pub fn write_chimera_rates<T: Write>(output: &mut T, stats: &[(String, usize, usize, usize)]) {
+    writeln!(output, "sample\twellstructured\tvalid\tchimeric\tchimera_rate")
+        .expect("Couldn't write header line to chimera rates!");
+
+    for (sample, well_structured, valid, chimeric) in stats {
+        writeln!(output, "{}\t{}\t{}\t{}\t{}",
+            sample, well_structured, valid, chimeric, *chimeric as f32 / *well_structured as f32)
+            .expect("Couldn't write line to chimera rates!");
+    }
+}
+
+#[derive(Debug, clap::Args)]
+pub struct MergeArgs {
+    /// Guide counts of previous runs, as SAMPLE=PATH, or just PATH to name the sample after the file.
+    #[arg(required = true)]
+    counts: Vec<String>,
+
+    /// Column of the guide counts to merge, like valid or deduplicated.
+    #[arg(long, default_value_t = String::from("valid"))]
+    column: String,
+
+    /// Guide x sample count matrix.
+    #[arg(short, long, default_value_t = String::from("stdout"))]
+    output_tsv: String,
+
+    /// Printed stats of previous runs, as SAMPLE=PATH or PATH. Lines may start with their sample, as when demultiplexing.
+    /// Give it once per file. Only read for --chimera-rates.
+    #[arg(long, num_args = 1, action = clap::ArgAction::Append, requires = "chimera_rates")]
+    stats: Vec<String>,
+
+    /// Chimeric fraction of well-structured reads for each sample, from --stats.
+    #[arg(long, requires = "stats")]
+    chimera_rates: Option<String>,
+}
+
+/// Splits SAMPLE=PATH, naming the sample after the file when it isn't given
+fn sample_and_path(arg: &str) -> (String, &str) {
+    match arg.split_once('=') {
+        Some((sample, path)) => (sample.to_string(), path),
+        None => {
+            let name = Path::new(arg).file_name().and_then(|name| name.to_str()).expect("Bad path!");
+            // drop every extension, like .tsv.gz
+            (name.split('.').next().unwrap_or(name).to_string(), arg)
+        },
+    }
+}
+
+/// Reads one column of a guide counts file
+fn read_counts(path: &str, column: &str) -> Vec<(String, u32)> {
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .from_reader(input::path_reader(path));
+
+    let headers = reader.headers().expect("Bad guide counts header!").clone();
+    let find = |name: &str| headers.iter().position(|header| header == name)
+        .unwrap_or_else(|| panic!("Guide counts {} has no {} column!", path, name));
+    let (guide, count) = (find("guide"), find(column));
+
+    reader.records()
+        .map(|result| {
+            let row = result.expect("Bad guide counts row!");
+            (row[guide].to_string(), row[count].parse().expect("Bad guide count!"))
+        })
+        .collect_vec()
+}
+
+/// Reads the (well-structured, valid, chimeric) stats of each sample in a printed stats file
+fn read_stats(sample: &str, path: &str) -> Vec<(String, usize, usize, usize)> {
+    let contents = fs::read_to_string(path).expect("Bad stats file!");
+
+    let mut samples: Vec<(String, HashMap<String, usize>)> = Vec::new();
+    for line in contents.lines().filter(|line| !line.is_empty()) {
+        let (line_sample, name, value) = match &line.split(',').collect_vec()[..] {
+            [name, value] => (sample, *name, *value),
+            [line_sample, name, value] => (*line_sample, *name, *value),
+            _ => panic!("Bad stats line {}!", line),
+        };
+        let value = value.parse().expect("Bad stat!");
+
+        match samples.iter_mut().find(|(s, _)| s == line_sample) {
+            Some((_, stats)) => { stats.insert(name.to_string(), value); },
+            None => samples.push((line_sample.to_string(), HashMap::from([(name.to_string(), value)]))),
+        }
+    }
+
+    samples.into_iter()
+        .map(|(sample, stats)| {
+            let get = |name: &str| *stats.get(name)
+                .unwrap_or_else(|| panic!("Stats of {} have no {}!", sample, name));
+            let (well_structured, valid, chimeric) = (get("wellstructured"), get("valid"), get("chimeric"));
+            (sample, well_structured, valid, chimeric)
+        })
+        .collect_vec()
+}
+
+pub fn merge_main(merge_args: &MergeArgs) {
+    let mut matrix = CountMatrix::new();
+    for arg in &merge_args.counts {
+        let (sample, path) = sample_and_path(arg);
+        matrix.add_sample(&sample, read_counts(path, &merge_args.column));
+    }
+    matrix.write(&mut output::path_writer(&merge_args.output_tsv));
+
+    if let Some(path) = &merge_args.chimera_rates {
+        let stats = merge_args.stats.iter()
+            .flat_map(|arg| {
+                let (sample, path) = sample_and_path(arg);
+                read_stats(&sample, path)
+            })
+            .collect_vec();
+        write_chimera_rates(&mut output::path_writer(path), &stats);
+    }
+}
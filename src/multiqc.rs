@@ -0,0 +1,96 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use itertools::Itertools;
+use serde_json::{json, Value};
+
+use crate::{find::CLASSES, output};
+
+/// What MultiQC is shown of one sample
+pub struct MultiqcSample {
+    pub name: String,
+    pub stats: Vec<(&'static str, usize)>,
+    // in the order of the classes
+    pub classes: Vec<(&'static str, usize)>,
+    pub valid_counts: Vec<(String, u32)>,
+}
+
+impl MultiqcSample {
+    fn stat(&self, name: &str) -> usize {
+        self.stats.iter()
+            .find(|(stat, _)| *stat == name)
+            .map_or(0, |(_, value)| *value)
+    }
+}
+
+/// Names a single-sample run after its input, as MultiQC would
+pub fn sample_name(input_fastq: &str) -> String {
+    let name = Path::new(input_fastq).file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(input_fastq);
+    name.split('.').next().unwrap_or(name).to_string()
+}
+
+fn write_section(dir: &Path, name: &str, section: Value) {
+    let path = dir.join(format!("chimera_{}_mqc.json", name));
+    serde_json::to_writer_pretty(output::path_writer(path.to_str().expect("Bad output path!")), &section)
+        .expect("Couldn't write MultiQC section!");
+}
+
+/// Writes MultiQC custom content: the classes of reads, the coverage of guides, and chimera rates for the general stats
+pub fn write_multiqc(dir: &str, samples: &[MultiqcSample]) {
+    let dir = Path::new(dir);
+    fs::create_dir_all(dir).expect("Couldn't create MultiQC directory!");
+
+    let classes: BTreeMap<_, _> = samples.iter()
+        .map(|sample| (&sample.name, CLASSES.iter().zip(&sample.classes)
+            .map(|((_, name), (_, count))| (*name, *count))
+            .collect::<BTreeMap<_, _>>()))
+        .collect();
+    // a list, so that the classes keep their order
+    let categories = CLASSES.iter().map(|(_, name)| *name).collect_vec();
+    write_section(dir, "classes", json!({
+        "id": "chimera_classes",
+        "section_name": "Chimera read classes",
+        "description": "Classification of each read by its structure and the guides its regions match.",
+        "plot_type": "bargraph",
+        "pconfig": { "id": "chimera_classes_plot", "title": "chimera: read classes", "ylab": "Reads" },
+        "categories": categories,
+        "data": classes,
+    }));
+
+    // how many guides have each number of valid reads
+    let coverage: BTreeMap<_, _> = samples.iter()
+        .map(|sample| (&sample.name, sample.valid_counts.iter()
+            .map(|(_, count)| *count)
+            .counts()
+            .into_iter()
+            .collect::<BTreeMap<_, _>>()))
+        .collect();
+    write_section(dir, "coverage", json!({
+        "id": "chimera_coverage",
+        "section_name": "Chimera guide coverage",
+        "description": "Number of guides with each number of valid reads, including dropouts with none.",
+        "plot_type": "linegraph",
+        "pconfig": { "id": "chimera_coverage_plot", "title": "chimera: guide coverage", "xlab": "Valid reads", "ylab": "Guides" },
+        "data": coverage,
+    }));
+
+    let general: BTreeMap<_, _> = samples.iter()
+        .map(|sample| {
+            let well_structured = sample.stat("wellstructured") as f64;
+            (&sample.name, json!({
+                "chimera_rate": sample.stat("chimeric") as f64 / well_structured * 100.0,
+                "valid_reads": sample.stat("valid"),
+            }))
+        })
+        .collect();
+    write_section(dir, "general", json!({
+        "id": "chimera_general",
+        "plot_type": "generalstats",
+        "pconfig": [
+            { "chimera_rate": { "title": "% Chimeric", "description": "Chimeric reads, as a percentage of well-structured reads", "max": 100, "suffix": "%" } },
+            { "valid_reads": { "title": "Valid", "description": "Reads matching one guide in every region", "format": "{:,.0f}" } },
+        ],
+        "data": general,
+    }));
+}
@@ -0,0 +1,65 @@
+use std::{collections::HashMap, io::Write};
+
+use crate::{reference::Ref, umi::ChimeraCounts};
+
+#[derive(Default)]
+struct PoolStats {
+    guides: usize,
+    valid: u32,
+    // chimeric reads with a parent in the pool, of which some have every parent in it,
+    // and some have no other parent identified
+    chimeric: u32,
+    intra_pool: u32,
+    unknown_pool: u32,
+}
+
+/// Writes the valid reads and chimera rate of each pool. Chimeras between pools count towards each of them,
+/// chimeras with only one guide identified are neither within nor between pools,
+/// and the chimera rate is the chimeric fraction of the valid and chimeric reads of the pool
+pub fn write_pool_stats<T: Write>(output: &mut T, reference: &Ref, valid_counts: &[(String, u32)], chimera_counts: &ChimeraCounts) {
+    writeln!(output, "pool\tguides\tvalid\tchimeric\tintra_pool\tinter_pool\tunknown_pool\tchimera_rate")
+        .expect("Couldn't write header line to pool stats!");
+
+    let mut pools: HashMap<&str, PoolStats> = HashMap::new();
+    for (guide, count) in valid_counts {
+        let pool = pools.entry(reference.pool(guide)).or_default();
+        pool.guides += 1;
+        pool.valid += count;
+    }
+    for (parents, reads) in chimera_counts.parents() {
+        let guides = parents.guides();
+        let unknown_pool = guides.len() < 2;
+        let intra_pool = !unknown_pool && reference.same_pool(&guides);
+
+        let mut seen = Vec::new();
+        for guide in guides {
+            let name = reference.pool(guide);
+            if seen.contains(&name) {
+                continue;
+            }
+            seen.push(name);
+
+            let pool = pools.entry(name).or_default();
+            pool.chimeric += reads;
+            if intra_pool {
+                pool.intra_pool += reads;
+            }
+            if unknown_pool {
+                pool.unknown_pool += reads;
+            }
+        }
+    }
+
+    // pools in the order of the reference, then guides without a pool
+    let names = reference.pools.iter().map(|pool| &pool[..]).chain([""]);
+    for name in names {
+        let Some(pool) = pools.get(name) else {
+            continue;
+        };
+        writeln!(output, "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            name, pool.guides, pool.valid, pool.chimeric, pool.intra_pool, pool.chimeric - pool.intra_pool - pool.unknown_pool,
+            pool.unknown_pool,
+            pool.chimeric as f32 / (pool.valid + pool.chimeric) as f32)
+            .expect("Couldn't write line to pool stats!");
+    }
+}
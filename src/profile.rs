@@ -0,0 +1,160 @@
+use std::{collections::HashMap, io::Write};
+
+use bio::alignment::{pairwise::Aligner, AlignmentOperation};
+use itertools::Itertools;
+
+use crate::{find::Regions, reference::{bases_match, FinalGuides, Region}};
+
+/// Counts of alignment events at one position of a reference region
+#[derive(Copy, Clone, Default, Debug)]
+pub struct PositionErrors {
+    pub matches: u32,
+    pub mismatches: u32,
+    // read bases inserted just before this position
+    pub insertions: u32,
+    // this position is missing from the read
+    pub deletions: u32,
+}
+
+impl PositionErrors {
+    /// Number of reads which aligned across this position
+    pub fn depth(&self) -> u32 {
+        self.matches + self.mismatches + self.deletions
+    }
+
+    fn add(&mut self, other: &PositionErrors) {
+        self.matches += other.matches;
+        self.mismatches += other.mismatches;
+        self.insertions += other.insertions;
+        self.deletions += other.deletions;
+    }
+}
+
+/// The per-position errors of one valid read, against the guide it was assigned to
+pub struct ReadErrors<'a> {
+    pub name: &'a str,
+    pub regions: Vec<(Region, Vec<PositionErrors>)>,
+}
+
+/// Aligns a region of a read against the reference sequence for that region.
+/// The reference is aligned end-to-end, while the read may overhang on either side
+fn align(reference: &[u8], read: &[u8]) -> Vec<PositionErrors> {
+    let score = |a: u8, b: u8| if bases_match(a, b) { 1i32 } else { -1i32 };
+    let mut aligner = Aligner::with_capacity(reference.len(), read.len(), -1, -1, score);
+    let alignment = aligner.semiglobal(reference, read);
+
+    let mut errors = vec![PositionErrors::default(); reference.len()];
+    let mut pos = alignment.xstart;
+    for op in alignment.operations {
+        match op {
+            AlignmentOperation::Match => { errors[pos].matches += 1; pos += 1 },
+            AlignmentOperation::Subst => { errors[pos].mismatches += 1; pos += 1 },
+            // a reference base with no counterpart in the read
+            AlignmentOperation::Ins => { errors[pos].deletions += 1; pos += 1 },
+            // a read base with no counterpart in the reference
+            AlignmentOperation::Del => { errors[pos.min(reference.len() - 1)].insertions += 1 },
+            AlignmentOperation::Xclip(_) | AlignmentOperation::Yclip(_) => {},
+        }
+    }
+
+    errors
+}
+
+/// Aligns each region of a valid read against the assigned guide
+pub fn read_errors<'a>(
+    regions: &Regions,
+    name: &'a str,
+    final_guides: &FinalGuides
+) -> ReadErrors<'a> {
+    // the RTT and PBS sit within the extension, for the guides which have them
+    let regions = [
+        (Region::Spacer, regions.spacer),
+        (Region::Extension, regions.extension),
+        (Region::Rtt, regions.extension),
+        (Region::Pbs, regions.extension),
+        (Region::Nicking, regions.nicking.unwrap_or_default()),
+    ].into_iter()
+        .filter(|(_, region_seq)| !region_seq.is_empty())
+        .filter_map(|(region, region_seq)| {
+            // an empty reference region has no positions to put errors at
+            let pattern = final_guides.region(region).get(name).filter(|pattern| !pattern.seq.is_empty())?;
+            Some((region, align(&pattern.seq, region_seq)))
+        })
+        .collect_vec();
+
+    ReadErrors { name, regions }
+}
+
+/// Error spectra of valid reads, accumulated across a run
+#[derive(Default)]
+pub struct ErrorProfile {
+    // summed over all guides, for each position of each region
+    positions: HashMap<Region, Vec<PositionErrors>>,
+    // summed over all positions, for each region of each guide
+    guides: HashMap<String, HashMap<Region, (u32, PositionErrors)>>,
+}
+
+impl ErrorProfile {
+    pub fn new() -> Self {
+        ErrorProfile::default()
+    }
+
+    pub fn add(&mut self, read: &ReadErrors) {
+        let guide = self.guides.entry(read.name.to_string()).or_default();
+
+        for (region, errors) in &read.regions {
+            let positions = self.positions.entry(*region).or_default();
+            if positions.len() < errors.len() {
+                positions.resize(errors.len(), PositionErrors::default());
+            }
+
+            let (reads, total) = guide.entry(*region).or_default();
+            *reads += 1;
+
+            for (pos, e) in errors.iter().enumerate() {
+                positions[pos].add(e);
+                total.add(e);
+            }
+        }
+    }
+
+    /// The errors at each position of each region, summed over all guides
+    pub fn positions(&self) -> Vec<(Region, &[PositionErrors])> {
+        self.positions.iter()
+            .sorted_by_key(|(region, _)| **region)
+            .map(|(region, positions)| (*region, &positions[..]))
+            .collect_vec()
+    }
+
+    /// Writes one row per position of each region, summed over all guides
+    pub fn write_positions<T: Write>(&self, output: &mut T) {
+        writeln!(output, "region\tposition\tdepth\tmismatches\tinsertions\tdeletions\tmismatch_rate\tinsertion_rate\tdeletion_rate")
+            .expect("Couldn't write header line to error profile!");
+
+        for (region, positions) in self.positions.iter().sorted_by_key(|(region, _)| **region) {
+            for (pos, e) in positions.iter().enumerate() {
+                let depth = e.depth() as f32;
+                writeln!(output, "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                    region.name(), pos + 1, e.depth(), e.mismatches, e.insertions, e.deletions,
+                    e.mismatches as f32 / depth, e.insertions as f32 / depth, e.deletions as f32 / depth)
+                    .expect("Couldn't write line to error profile!");
+            }
+        }
+    }
+
+    /// Writes one row per region of each guide, summed over all positions
+    pub fn write_guides<T: Write>(&self, output: &mut T) {
+        writeln!(output, "guide\tregion\treads\tbases\tmismatches\tinsertions\tdeletions\tmismatch_rate\tinsertion_rate\tdeletion_rate")
+            .expect("Couldn't write header line to guide error profile!");
+
+        for (name, regions) in self.guides.iter().sorted_by_key(|(name, _)| *name) {
+            for (region, (reads, e)) in regions.iter().sorted_by_key(|(region, _)| **region) {
+                let bases = e.depth() as f32;
+                writeln!(output, "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                    name, region.name(), reads, e.depth(), e.mismatches, e.insertions, e.deletions,
+                    e.mismatches as f32 / bases, e.insertions as f32 / bases, e.deletions as f32 / bases)
+                    .expect("Couldn't write line to guide error profile!");
+            }
+        }
+    }
+}
@@ -1,9 +1,9 @@
-use std::{cmp::Ordering, collections::HashMap, hash::Hash};
+use std::{cmp::Ordering, collections::{HashMap, HashSet}, ffi::OsStr, hash::Hash, io::Read, ops::{Add, Deref}, path::Path, sync::Mutex};
 
-use bio::pattern_matching::myers::{Myers, long};
+use bio::pattern_matching::myers::{Myers, MyersBuilder, long};
 use itertools::Itertools;
 
-use crate::Args;
+use crate::{exact::ExactGuides, kmer::KmerIndex, Args};
 
 #[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
 pub struct Mismatch {
@@ -21,6 +21,14 @@ impl Mismatch {
     }
 }
 
+impl Add for Mismatch {
+    type Output = Mismatch;
+
+    fn add(self, other: Mismatch) -> Mismatch {
+        Mismatch::new(self.len + other.len, self.dist + other.dist)
+    }
+}
+
 impl Ord for Mismatch {
     fn cmp(&self, other: &Self) -> Ordering {
         self.error_rate().partial_cmp(&other.error_rate()).unwrap()
@@ -34,18 +42,55 @@ impl PartialOrd for Mismatch {
 }
 
 
+/// The default cys4 hairpin, between each guide and the next
+pub const CYS4: &str = "GTTCACTGCCGTATAGGCAG";
+/// The default sgRNA scaffold
+pub const SCAFFOLD: &str = "GTTTTAGAGCTAGAAATAGCAAGTTAAAATAAGGCTAGTCCGTTATCAACTTGAAAAAGTGGCACCGAGTCGGTGC";
+/// The default edit distance allowed in the linker and 3' motif
+pub const MOTIF_ERROR_RATE: f32 = 0.1;
+
 #[derive(Clone, PartialEq, Eq, Hash)]
 pub enum VarMyers {
     Short(Myers::<u64>),
     Long(long::Myers::<u64>)
 }
 
+/// IUPAC degenerate bases, and the bases they stand for
+const IUPAC: [(u8, &[u8]); 11] = [
+    (b'R', b"AG"),
+    (b'Y', b"CT"),
+    (b'S', b"CG"),
+    (b'W', b"AT"),
+    (b'K', b"GT"),
+    (b'M', b"AC"),
+    (b'B', b"CGTSYK"),
+    (b'D', b"AGTRWK"),
+    (b'H', b"ACTMWY"),
+    (b'V', b"ACGMRS"),
+    (b'N', b"ACGTMRWSYKVHDB"),
+];
+
+/// Whether a base in a read matches a base in the reference. 
+/// Reference bases may be degenerate, and an N in the read matches anything
+pub fn bases_match(reference: u8, read: u8) -> bool {
+    reference == read
+        || read == b'N'
+        || IUPAC.iter().any(|(base, equivalents)| *base == reference && equivalents.contains(&read))
+}
+
 impl VarMyers {
     fn new(seq: &[u8]) -> Self {
+        // degenerate bases in the reference match what they stand for, and Ns in reads match anything
+        let mut builder = MyersBuilder::new();
+        for (base, equivalents) in IUPAC {
+            builder.ambig(base, equivalents);
+        }
+        builder.text_wildcard(b'N');
+
         if seq.len() <= 64 {
-            VarMyers::Short(Myers::<u64>::new(seq))
+            VarMyers::Short(builder.build_64(seq))
         } else {
-            VarMyers::Long(long::Myers::<u64>::new(seq))
+            VarMyers::Long(builder.build_long_64(seq))
         }
     }
     
@@ -65,6 +110,14 @@ impl VarMyers {
         }
     }
 
+    /// Where every match ends, just past its last base, and its distance
+    fn find_ends(&self, seq: &[u8], edit_dist: u8) -> Vec<(usize, usize)> {
+        match self {
+            VarMyers::Short(s) => s.find_all_end(seq, edit_dist).map(|(end, d)| (end + 1, d as usize)).collect_vec(),
+            VarMyers::Long(l) => l.find_all_end(seq, edit_dist.into()).map(|(end, d)| (end + 1, d)).collect_vec(),
+        }
+    }
+
     fn find_best_end2(&self, seq: &[u8], edit_dist: u8) -> Option<usize> {
         let dist = match self {
             VarMyers::Short(s) => {let (_, dist) = s.find_best_end(seq); dist },
@@ -80,12 +133,42 @@ impl VarMyers {
 
 }
 
+/// The guide-specific regions of a read, in the order they appear
+#[derive(Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
+pub enum Region {
+    Spacer,
+    Extension,
+    Rtt,
+    Pbs,
+    Nicking,
+}
+
+impl Region {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Region::Spacer => "spacer",
+            Region::Extension => "extension",
+            Region::Rtt => "rtt",
+            Region::Pbs => "pbs",
+            Region::Nicking => "nicking",
+        }
+    }
+}
+
 #[derive(Clone, PartialEq, Eq, Hash)]
 pub struct Pattern {
     pub seq: Vec<u8>,
     pub myers: VarMyers
 }
 
+/// A pattern searched for where its matches start as well as end, like the cys4 and scaffold.
+/// Finding starts keeps traceback state in the Myers itself, so each worker thread searches its own copy
+pub struct TracedPattern {
+    pub pattern: Pattern,
+    // a copy for any thread outside the pool, then one for each rayon worker
+    scratch: Vec<Mutex<VarMyers>>,
+}
+
 #[derive(Clone)]
 pub struct NamedPattern {
     pub name: String,
@@ -93,9 +176,24 @@ pub struct NamedPattern {
 }
 
 pub struct Ref {
-    pub cys4: Pattern,
-    pub scaffold: Pattern,
-    pub guides: Vec<Guide>
+    pub cys4: TracedPattern,
+    pub scaffold: TracedPattern,
+    // whether reads have the second (cys4 - nicking - scaffold) segment
+    pub nicking: bool,
+    pub guides: Vec<Guide>,
+    // the linker and 3' motif of epegRNAs, for guides which don't give their own
+    pub linker: Option<Pattern>,
+    pub motif: Option<Pattern>,
+    // guides which do give their own
+    pub guide_linkers: HashMap<String, Pattern>,
+    pub guide_motifs: HashMap<String, Pattern>,
+    // edit distance allowed in the linker and motif, tighter than in the guides
+    pub motif_error_rate: f32,
+    // extra reference columns, carried through to the outputs
+    pub annotation_columns: Vec<String>,
+    // sub-pools the guides were ordered in, in the order they first appear
+    pub pools: Vec<String>,
+    guide_index: HashMap<String, usize>,
 }
 
 #[derive(Clone)]
@@ -103,18 +201,56 @@ pub struct Guide {
     pub name: String,
     pub spacer: Pattern,
     pub extension: Pattern,
-    pub nicking: Pattern
+    // PE2-style guides have no nicking sgRNA
+    pub nicking: Option<Pattern>,
+    // the extension is RTT + PBS; when both are given, they're matched separately
+    pub rtt: Option<Pattern>,
+    pub pbs: Option<Pattern>,
+    // epegRNAs have a linker and a structured motif after the PBS
+    pub linker: Option<Pattern>,
+    pub motif: Option<Pattern>,
+    // relative abundance the guide was designed to have, for pools with intentional skew
+    pub expected: Option<f64>,
+    // sub-pool of the library the guide was ordered in
+    pub pool: Option<String>,
+    // values of the reference's annotation columns
+    pub annotations: Vec<String>,
 }
 
 impl Guide {
-    fn new(record: TSVRecord) -> Self {
+    fn new(record: TSVRecord, annotations: Vec<String>) -> Self {
+        let (rtt, pbs) = match (record.rtt, record.pbs) {
+            (Some(rtt), Some(pbs)) => (Some(rtt), Some(pbs)),
+            (None, None) => (None, None),
+            _ => panic!("Guide {} needs both an RTT and a PBS, or neither!", record.name),
+        };
+
+        // the extension can be left out if the RTT and PBS are given
+        let extension = match (&rtt, &pbs) {
+            (Some(rtt), Some(pbs)) if record.extension.is_empty() => format!("{}{}", rtt, pbs),
+            _ if record.extension.is_empty() => panic!("Guide {} needs an extension, or an RTT and a PBS!", record.name),
+            _ => record.extension,
+        };
+
         Guide {
             name: record.name,
             spacer: Pattern::new(record.spacer.as_bytes(), ),
-            extension: Pattern::new(record.extension.as_bytes()),
-            nicking: Pattern::new(record.nicking.as_bytes()),
+            extension: Pattern::new(extension.as_bytes()),
+            nicking: record.nicking.map(|nicking| Pattern::new(nicking.as_bytes())),
+            rtt: rtt.map(|rtt| Pattern::new(rtt.as_bytes())),
+            pbs: pbs.map(|pbs| Pattern::new(pbs.as_bytes())),
+            linker: record.linker.map(|linker| Pattern::new(linker.as_bytes())),
+            motif: record.motif.map(|motif| Pattern::new(motif_seq(&motif).as_bytes())),
+            expected: record.expected,
+            pool: record.pool,
+            annotations,
         }
     }
+
+    /// Whether the RTT and PBS are matched separately, instead of the whole extension
+    pub fn split_extension(&self) -> bool {
+        self.rtt.is_some() && self.pbs.is_some()
+    }
 }
 
 #[derive(Clone)]
@@ -123,11 +259,43 @@ pub struct EfficientGuide {
     pub names: Vec<String>
 }
 
+/// The patterns of one region of every guide, indexed to shortlist those worth searching for
+#[derive(Clone)]
+pub struct EfficientRegion {
+    pub guides: Vec<EfficientGuide>,
+    index: KmerIndex,
+}
+
+impl EfficientRegion {
+    fn new(guides: Vec<EfficientGuide>, error_rate: f32) -> Self {
+        let patterns = guides.iter().map(|g| &g.pattern.seq[..]).collect_vec();
+        let index = KmerIndex::new(&patterns, error_rate);
+
+        EfficientRegion { guides, index }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.guides.is_empty()
+    }
+
+    /// The guides which could match a sequence within the error rate
+    pub fn candidates(&self, seq: &[u8], error_rate: f32) -> Vec<&EfficientGuide> {
+        match self.index.candidates(seq, error_rate) {
+            Some(candidates) => candidates.into_iter().map(|i| &self.guides[i]).collect_vec(),
+            None => self.guides.iter().collect_vec(),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct EfficientGuides {
-    pub spacers: Vec<EfficientGuide>,
-    pub extensions: Vec<EfficientGuide>,
-    pub nickings: Vec<EfficientGuide>,
+    pub spacers: EfficientRegion,
+    pub extensions: EfficientRegion,
+    pub nickings: EfficientRegion,
+    pub rt_templates: EfficientRegion,
+    pub primer_binding_sites: EfficientRegion,
+    // names of the guides without a nicking sgRNA
+    pub unnicked: HashSet<String>,
 }
 
 impl NamedPattern {
@@ -140,7 +308,7 @@ impl NamedPattern {
 }
 
 impl Pattern {
-    fn new(seq: &[u8]) -> Pattern {
+    pub fn new(seq: &[u8]) -> Pattern {
         Pattern {
             seq: seq.to_owned(),
             myers: VarMyers::new(seq),
@@ -158,10 +326,38 @@ impl Pattern {
         })
     }
 
+    /// Where every match ends, just past its last base, without finding where it starts
+    pub fn get_match_ends(&self, seq: &[u8], error_rate: f32) -> Vec<(usize, Mismatch)> {
+        let edit_dist = (error_rate * (self.seq.len() as f32)).floor() as u8;
+
+        self.myers.find_ends(seq, edit_dist).into_iter()
+            .map(|(end, dist)| (end, Mismatch::new(self.seq.len(), dist)))
+            .collect_vec()
+    }
+}
+
+impl TracedPattern {
+    pub fn new(seq: &[u8]) -> TracedPattern {
+        let pattern = Pattern::new(seq);
+        let scratch = (0..=rayon::current_num_threads())
+            .map(|_| Mutex::new(pattern.myers.clone()))
+            .collect_vec();
+
+        TracedPattern { pattern, scratch }
+    }
+
     pub fn get_matches(&self, seq: &[u8], error_rate: f32) -> Vec<(usize, usize, Mismatch)> {
         let edit_dist = (error_rate * (self.seq.len() as f32)).floor() as u8;
 
-        let matches = self.myers.clone().find_all(seq, edit_dist).iter()
+        // workers each have their own copy, and threads outside the pool share the first or search a fresh one
+        let slot = rayon::current_thread_index()
+            .filter(|i| i + 1 < self.scratch.len())
+            .map_or(0, |i| i + 1);
+        let matches = match self.scratch[slot].try_lock() {
+            Ok(mut myers) => myers.find_all(seq, edit_dist),
+            Err(_) => self.myers.clone().find_all(seq, edit_dist),
+        };
+        let matches = matches.iter()
             .map(|(s, e, d)| (*s, *e, Mismatch::new(self.seq.len(), *d as usize)))
             .sorted_by_key(|(_, _, dist)| *dist);
 
@@ -184,62 +380,270 @@ impl Pattern {
     }
 }
 
+impl Deref for TracedPattern {
+    type Target = Pattern;
+
+    fn deref(&self) -> &Pattern {
+        &self.pattern
+    }
+}
+
 impl Ref {
-    pub fn new(arg: &Args) -> Ref {
+    pub fn new(arg: &Args, reference_tsv: &str) -> Ref {
+        let (guides, annotation_columns) = parse_reference(reference_tsv, arg.reference_header, arg.reference_columns.as_deref());
+
         Ref {
-            cys4: Pattern::new(arg.cys4.as_bytes()),
-            scaffold: Pattern::new(arg.scaffold.as_bytes()),
-            guides: parse_reference(&arg.reference_tsv),
+            cys4: TracedPattern::new(arg.cys4.as_bytes()),
+            scaffold: TracedPattern::new(arg.scaffold.as_bytes()),
+            nicking: !arg.no_nicking,
+            linker: arg.linker.as_ref().map(|linker| Pattern::new(linker.as_bytes())),
+            motif: arg.motif.as_ref().map(|motif| Pattern::new(motif_seq(motif).as_bytes())),
+            motif_error_rate: arg.motif_error_rate,
+            ..Ref::from_guides(guides, annotation_columns)
+        }
+    }
+
+    /// A reference from the text of a headerless, tab-separated reference file, with the default
+    /// cys4, scaffold and nicking guides, and no linker or motif. For building one without any files
+    pub fn from_tsv(tsv: &str) -> Ref {
+        let reader = csv::ReaderBuilder::new()
+            .delimiter(b'\t')
+            .has_headers(false)
+            .from_reader(tsv.as_bytes());
+        let (guides, annotation_columns) = read_reference(reader, None);
+
+        Ref::from_guides(guides, annotation_columns)
+    }
+
+    fn from_guides(guides: Vec<Guide>, annotation_columns: Vec<String>) -> Ref {
+        Ref {
+            cys4: TracedPattern::new(CYS4.as_bytes()),
+            scaffold: TracedPattern::new(SCAFFOLD.as_bytes()),
+            nicking: true,
+            linker: None,
+            motif: None,
+            guide_linkers: guides.iter()
+                .filter_map(|g| Some((g.name.clone(), g.linker.clone()?)))
+                .collect(),
+            guide_motifs: guides.iter()
+                .filter_map(|g| Some((g.name.clone(), g.motif.clone()?)))
+                .collect(),
+            motif_error_rate: MOTIF_ERROR_RATE,
+            pools: guides.iter()
+                .filter_map(|g| g.pool.clone())
+                .unique()
+                .collect_vec(),
+            guide_index: guides.iter().enumerate()
+                .map(|(i, g)| (g.name.clone(), i))
+                .collect(),
+            guides,
+            annotation_columns,
+        }
+    }
+
+    pub fn guide(&self, name: &str) -> Option<&Guide> {
+        self.guide_index.get(name).map(|i| &self.guides[*i])
+    }
+
+    /// The pool of a guide, or blank if it has none
+    pub fn pool(&self, name: &str) -> &str {
+        self.guide(name)
+            .and_then(|guide| guide.pool.as_deref())
+            .unwrap_or("")
+    }
+
+    /// Whether guides all come from the same pool
+    pub fn same_pool(&self, names: &[&str]) -> bool {
+        names.iter().map(|name| self.pool(name)).all_equal()
+    }
+
+    /// The annotations of a guide, or blanks if there's no guide
+    pub fn annotations(&self, name: Option<&str>) -> Vec<&str> {
+        match name.and_then(|name| self.guide(name)) {
+            Some(guide) => guide.annotations.iter().map(|a| &a[..]).collect_vec(),
+            None => vec![""; self.annotation_columns.len()],
+        }
+    }
+
+    /// The linker and 3' motif expected after the PBS of a guide, or of any guide
+    pub fn linker_motif(&self, name: Option<&str>) -> (Option<&Pattern>, Option<&Pattern>) {
+        let linker = name.and_then(|name| self.guide_linkers.get(name)).or(self.linker.as_ref());
+        let motif = name.and_then(|name| self.guide_motifs.get(name)).or(self.motif.as_ref());
+
+        (linker, motif)
+    }
+}
+
+/// Edit distance of a reference sequence aligned in full to the end of a read sequence.
+/// With a free start, the alignment may begin anywhere in the read; otherwise it starts at its beginning
+pub fn end_distance(reference: &[u8], read: &[u8], free_start: bool) -> usize {
+    let mut previous = (0..=read.len())
+        .map(|j| if free_start { 0 } else { j })
+        .collect_vec();
+
+    for (i, &reference_base) in reference.iter().enumerate() {
+        let mut row = vec![i + 1; read.len() + 1];
+        for (j, &read_base) in read.iter().enumerate() {
+            let substitution = previous[j] + usize::from(!bases_match(reference_base, read_base));
+            row[j + 1] = substitution.min(previous[j + 1] + 1).min(row[j] + 1);
         }
+        previous = row;
     }
+
+    previous[read.len()]
+}
+
+/// Motifs commonly used to protect the 3' end of epegRNAs (Nelson et al. 2022)
+const MOTIFS: [(&str, &str); 2] = [
+    ("tevopreq1", "CGCGGTTCTATCTAGTTACGCGTTAAACCAACTAGAA"),
+    ("mpknot", "GGGTCAGGAGCCCCCCCCCTGAACCCAGGATAACCCTCAAAGTCGGGGGGCAACCC"),
+];
+
+/// Either looks up a motif by name, or takes it as a sequence
+fn motif_seq(motif: &str) -> String {
+    MOTIFS.iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(motif))
+        .map(|(_, seq)| seq.to_string())
+        .unwrap_or(motif.to_string())
 }
 
 #[derive(Debug, serde::Deserialize)]
 struct TSVRecord {
     name: String,
     spacer: String,
+    #[serde(default)]
     extension: String,
-    nicking: String,
+    #[serde(default)]
+    nicking: Option<String>,
+    #[serde(default)]
+    rtt: Option<String>,
+    #[serde(default)]
+    pbs: Option<String>,
+    #[serde(default)]
+    linker: Option<String>,
+    #[serde(default)]
+    motif: Option<String>,
+    #[serde(default)]
+    expected: Option<f64>,
+    #[serde(default)]
+    pool: Option<String>,
 }
 
-fn parse_reference(reference_tsv: &str) -> Vec<Guide> {
-    let mut reader = csv::ReaderBuilder::new()
-        .delimiter(b'\t')
-        .has_headers(false)
-        .from_path(reference_tsv)
+/// The fields of `TSVRecord`, in its order
+const FIELDS: [&str; 10] = ["name", "spacer", "extension", "nicking", "rtt", "pbs", "linker", "motif", "expected", "pool"];
+
+// references without a header only give the first fields, by position. Any columns after them are
+// left alone, as legacy references use them for annotations rather than RTTs and PBSs
+const POSITIONAL_FIELDS: usize = 4;
+
+/// Parses a mapping like `name=guide_id,spacer=protospacer` from fields to column names
+fn parse_columns(columns: &str) -> HashMap<String, String> {
+    columns.split(',')
+        .map(|pair| {
+            let (field, column) = pair.split_once('=')
+                .expect("Bad reference column mapping! Expected field=column");
+            if !FIELDS.contains(&field.trim()) {
+                panic!("Unknown reference field {}! Expected one of {}", field, FIELDS.join(", "));
+            }
+
+            (column.trim().to_string(), field.trim().to_string())
+        })
+        .collect()
+}
+
+/// Renames the columns of a header to the fields they hold.
+/// Columns which aren't fields are left alone, and end up ignored
+fn field_headers(headers: &csv::StringRecord, mapping: &HashMap<String, String>) -> csv::StringRecord {
+    headers.iter()
+        .map(|header| {
+            if let Some(field) = mapping.get(header) {
+                field.as_str()
+            } else if mapping.values().any(|field| field.eq_ignore_ascii_case(header)) {
+                // another column has been mapped to this field
+                ""
+            } else if let Some(field) = FIELDS.iter().find(|field| field.eq_ignore_ascii_case(header)) {
+                field
+            } else {
+                header
+            }
+        })
+        .collect()
+}
+
+fn parse_reference(reference_tsv: &str, has_headers: bool, columns: Option<&str>) -> (Vec<Guide>, Vec<String>) {
+    let path = Path::new(reference_tsv);
+
+    // csv files are comma-separated; anything else is taken to be tab-separated
+    let delimiter = if path.extension() == Some(OsStr::new("csv")) { b',' } else { b'\t' };
+
+    let reader = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .has_headers(has_headers || columns.is_some())
+        .from_path(path)
         .expect("Bad reference file!");
 
+    read_reference(reader, columns)
+}
+
+/// Reads the guides of a reference, and the names of its annotation columns
+fn read_reference<R: Read>(mut reader: csv::Reader<R>, columns: Option<&str>) -> (Vec<Guide>, Vec<String>) {
+    let headers = reader.has_headers().then(|| {
+        let mapping = columns.map(parse_columns).unwrap_or_default();
+        field_headers(reader.headers().expect("Bad reference header!"), &mapping)
+    });
+
+    // any named columns which aren't fields are kept as annotations
+    let annotation_columns = headers.iter()
+        .flat_map(|headers| headers.iter().enumerate())
+        .filter(|(_, header)| !header.is_empty() && !FIELDS.contains(header))
+        .map(|(i, header)| (i, header.to_string()))
+        .collect_vec();
+
     let mut guides = Vec::new();
 
-    for result in reader.deserialize() {
+    for result in reader.records() {
         // read a record
-        let record: TSVRecord = result
+        let row = result
             .expect("Bad reference row!");
+        let record: TSVRecord = match &headers {
+            Some(headers) => row.deserialize(Some(headers)),
+            None => row.iter().take(POSITIONAL_FIELDS).collect::<csv::StringRecord>().deserialize(None),
+        }.expect("Bad reference row!");
+        let annotations = annotation_columns.iter()
+            .map(|(i, _)| row.get(*i).unwrap_or_default().to_string())
+            .collect_vec();
 
         // add it to the guides
-        guides.push(Guide::new(record));
+        guides.push(Guide::new(record, annotations));
     }
 
-    guides
+    (guides, annotation_columns.into_iter().map(|(_, header)| header).collect_vec())
 }
 
 impl EfficientGuides {
-    pub fn new(guides: &[Guide]) -> EfficientGuides {
-        fn make_guides(guides: &[Guide], f: fn(&Guide) -> Pattern) -> Vec<EfficientGuide> {
-            let all_names = guides.iter().map(|g| (g.name.clone(), f(g)));
+    pub fn new(guides: &[Guide], error_rate: f32) -> EfficientGuides {
+        let make_guides = |f: fn(&Guide) -> Option<Pattern>| {
+            let all_names = guides.iter().filter_map(|g| Some((g.name.clone(), f(g)?)));
 
-            all_names.group_by(|(_, pattern)| pattern.to_owned()).into_iter()
+            let guides = all_names.group_by(|(_, pattern)| pattern.to_owned()).into_iter()
                 .map(|(pattern, group)| 
                     EfficientGuide { 
                         pattern: pattern.clone(), 
                         names: group.map(|(name, _)| name).collect_vec()})
-                .collect_vec()
-        }
+                .collect_vec();
+            EfficientRegion::new(guides, error_rate)
+        };
 
         EfficientGuides { 
-            spacers: make_guides(guides, |g| g.spacer.clone()), 
-            extensions: make_guides(guides, |g| g.extension.clone()), 
-            nickings: make_guides(guides, |g| g.nicking.clone())
+            spacers: make_guides(|g| Some(g.spacer.clone())), 
+            extensions: make_guides(|g| (!g.split_extension()).then(|| g.extension.clone())), 
+            nickings: make_guides(|g| g.nicking.clone()),
+            rt_templates: make_guides(|g| g.rtt.clone()),
+            primer_binding_sites: make_guides(|g| g.pbs.clone()),
+            unnicked: guides.iter()
+                .filter(|g| g.nicking.is_none())
+                .map(|g| g.name.clone())
+                .collect(),
         }
     }
 }
@@ -248,23 +652,283 @@ pub struct FinalGuides {
     pub spacers: HashMap<String, Pattern>,
     pub extensions: HashMap<String, Pattern>,
     pub nickings: HashMap<String, Pattern>,
+    pub rt_templates: HashMap<String, Pattern>,
+    pub primer_binding_sites: HashMap<String, Pattern>,
+    // names of the guides in the order their spacers are indexed
+    spacer_names: Vec<String>,
+    spacer_index: KmerIndex,
+    // lookups of exact sequences, for references which can have them
+    exact: Option<ExactGuides>,
 }
 
 impl FinalGuides {
-    pub fn new(guides: &[Guide]) -> FinalGuides {
-        FinalGuides {
-            spacers: guides.iter()
-            .map(|Guide {name, spacer, extension: _, nicking: _ }| 
+    pub fn new(guides: &[Guide], error_rate: f32, exact_neighbours: bool) -> FinalGuides {
+        let spacers: HashMap<String, Pattern> = guides.iter()
+            .map(|Guide {name, spacer, .. }| 
                 (name.clone(), spacer.clone())
-            ).collect(),
+            ).collect();
+        // index each name once, as the spacers keep only the last guide of a name
+        let spacer_names = spacers.keys().cloned().collect_vec();
+        let spacer_seqs = spacer_names.iter().map(|name| &spacers[name].seq[..]).collect_vec();
+
+        let mut final_guides = FinalGuides {
+            spacer_index: KmerIndex::new(&spacer_seqs, error_rate),
+            spacer_names,
+            exact: None,
+            spacers,
             extensions: guides.iter()
-            .map(|Guide {name, spacer: _, extension, nicking: _ }| 
+            .filter(|g| !g.split_extension())
+            .map(|Guide {name, extension, .. }| 
                 (name.clone(), extension.clone())
             ).collect(),
             nickings: guides.iter()
-            .map(|Guide {name, spacer: _, extension: _, nicking }| 
-                (name.clone(), nicking.clone())
+            .filter_map(|Guide {name, nicking, .. }| 
+                Some((name.clone(), nicking.clone()?))
+            ).collect(),
+            rt_templates: guides.iter()
+            .filter_map(|Guide {name, rtt, .. }| 
+                Some((name.clone(), rtt.clone()?))
+            ).collect(),
+            primer_binding_sites: guides.iter()
+            .filter_map(|Guide {name, pbs, .. }| 
+                Some((name.clone(), pbs.clone()?))
             ).collect(),
+        };
+        final_guides.exact = ExactGuides::new(
+            &final_guides.spacers, &final_guides.extensions, &final_guides.nickings, exact_neighbours);
+        final_guides
+    }
+
+    /// The best guides found by looking up the regions of a read, if that's enough to be sure of them
+    pub fn exact_matches(
+        &self,
+        spacer_seq: &[u8],
+        extension_seq: &[u8],
+        nicking_seq: Option<&[u8]>,
+        error_rate: f32
+    ) -> Option<Vec<(&str, Mismatch)>> {
+        self.exact.as_ref()?.matches(spacer_seq, extension_seq, nicking_seq, error_rate)
+    }
+
+    /// Names of the guides whose spacers could match a sequence within the error rate
+    pub fn spacer_candidates(&self, seq: &[u8], error_rate: f32) -> Vec<&str> {
+        match self.spacer_index.candidates(seq, error_rate) {
+            Some(candidates) => candidates.into_iter().map(|i| &self.spacer_names[i][..]).collect_vec(),
+            None => self.spacer_names.iter().map(|name| &name[..]).collect_vec(),
         }
     }
-}
\ No newline at end of file
+
+    /// If the spacers are too short to index at this error rate, the error rate they would be indexed below
+    pub fn spacers_indexed_below(&self) -> Option<f32> {
+        self.spacer_index.indexed_below()
+    }
+
+    pub fn region(&self, region: Region) -> &HashMap<String, Pattern> {
+        match region {
+            Region::Spacer => &self.spacers,
+            Region::Extension => &self.extensions,
+            Region::Rtt => &self.rt_templates,
+            Region::Pbs => &self.primer_binding_sites,
+            Region::Nicking => &self.nickings,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{find, synthetic::Bases};
+
+    fn tsv(guides: &[[Vec<u8>; 3]]) -> String {
+        guides.iter().enumerate()
+            .map(|(i, seqs)| format!("g{}\t{}\n", i, seqs.iter().map(|seq| String::from_utf8_lossy(seq)).join("\t")))
+            .collect()
+    }
+
+    /// Random guides, every tenth one substitution from the one before it, some with degenerate bases,
+    /// and some with regions shorter than any q-gram
+    fn library(bases: &mut Bases, guides: usize, degenerate: bool) -> Vec<[Vec<u8>; 3]> {
+        let mut library: Vec<[Vec<u8>; 3]> = Vec::new();
+        for i in 0..guides {
+            let mut seqs = match library.last() {
+                Some(previous) if i % 10 == 0 => {
+                    let mut seqs = previous.clone();
+                    let pos = bases.below(seqs[0].len());
+                    seqs[0][pos] = if seqs[0][pos] == b'A' { b'C' } else { b'A' };
+                    seqs
+                },
+                _ if i % 25 == 0 => [bases.seq(20), bases.seq(4), bases.seq(4)],
+                _ => {
+                    let extension_len = 20 + bases.below(30);
+                    [bases.seq(20), bases.seq(extension_len), bases.seq(20)]
+                },
+            };
+            if degenerate && i % 7 == 0 {
+                let (region, pos) = (bases.below(3), bases.below(4));
+                seqs[region][pos] = [b'R', b'Y', b'N', b'W'][bases.below(4)];
+            }
+            library.push(seqs);
+        }
+        library
+    }
+
+    /// Substitutes, inserts or deletes a base of a read, or makes it an N
+    fn edit(bases: &mut Bases, read: &mut Vec<u8>) {
+        let pos = bases.below(read.len() + 1);
+        match bases.below(4) {
+            0 if pos < read.len() => read[pos] = bases.base(),
+            1 => read.insert(pos, bases.base()),
+            2 if pos < read.len() => { read.remove(pos); },
+            _ if pos < read.len() => read[pos] = b'N',
+            _ => {},
+        }
+    }
+
+    /// A read's region of a guide, with a few edits, or cut short
+    fn mutate(bases: &mut Bases, seq: &[u8]) -> Vec<u8> {
+        // reads have one of the bases a degenerate reference base stands for
+        let mut read = seq.iter()
+            .map(|base| IUPAC.iter().find(|(b, _)| b == base).map_or(*base, |(_, equivalents)| equivalents[0]))
+            .collect_vec();
+
+        if bases.below(10) == 0 {
+            read.truncate(bases.below(5));
+            return read;
+        }
+        for _ in 0..bases.below(4) {
+            edit(bases, &mut read);
+        }
+        read
+    }
+
+    /// The guides of a library, searched without the q-gram index or exact lookups
+    fn exhaustive(guides: &[Guide], error_rate: f32) -> (FinalGuides, EfficientGuides) {
+        let mut final_guides = FinalGuides::new(guides, error_rate, false);
+        final_guides.spacer_index = KmerIndex::new(&[], error_rate);
+        final_guides.exact = None;
+
+        let mut efficient_guides = EfficientGuides::new(guides, error_rate);
+        for region in [&mut efficient_guides.spacers, &mut efficient_guides.extensions, &mut efficient_guides.nickings,
+            &mut efficient_guides.rt_templates, &mut efficient_guides.primer_binding_sites] {
+            region.index = KmerIndex::new(&[], error_rate);
+        }
+        (final_guides, efficient_guides)
+    }
+
+    fn sorted(matches: Vec<(&str, Mismatch)>) -> Vec<(&str, Mismatch)> {
+        matches.into_iter().sorted_by_key(|(name, mismatch)| (*name, mismatch.len, mismatch.dist)).collect_vec()
+    }
+
+    #[test]
+    fn index_finds_every_match() {
+        for (degenerate, error_rate) in [(false, 0.1), (false, 0.15), (true, 0.1), (true, 0.15)] {
+            let mut bases = Bases(0x9e3779b97f4a7c15);
+            let library = library(&mut bases, 200, degenerate);
+            let reference = Ref::from_tsv(&tsv(&library));
+            let final_guides = FinalGuides::new(&reference.guides, error_rate, false);
+            // the default error rate is too high to index 20 nt spacers, so check at rates which index them
+            assert_eq!(final_guides.spacers_indexed_below(), None);
+            assert_eq!(FinalGuides::new(&reference.guides, 0.25, false).spacers_indexed_below(), Some(0.2));
+            let efficient_guides = EfficientGuides::new(&reference.guides, error_rate);
+            let (all_final_guides, all_efficient_guides) = exhaustive(&reference.guides, error_rate);
+
+            for _ in 0..300 {
+                let guide = &library[bases.below(library.len())];
+                let [spacer, extension, nicking] = guide.clone().map(|seq| mutate(&mut bases, &seq));
+
+                let candidates = final_guides.spacer_candidates(&spacer, error_rate);
+                for (name, pattern) in &final_guides.spacers {
+                    if pattern.get_best_match(&spacer, error_rate).is_some() {
+                        assert!(candidates.contains(&&name[..]), "{} not a candidate for {}", name, String::from_utf8_lossy(&spacer));
+                    }
+                }
+                for (region, seq) in [(&efficient_guides.spacers, &spacer), (&efficient_guides.extensions, &extension),
+                    (&efficient_guides.nickings, &nicking)] {
+                    let candidates = region.candidates(seq, error_rate);
+                    for guide in &region.guides {
+                        if guide.pattern.get_best_match(seq, error_rate).is_some() {
+                            assert!(candidates.iter().any(|candidate| candidate.names == guide.names),
+                                "{:?} not a candidate for {}", guide.names, String::from_utf8_lossy(seq));
+                        }
+                    }
+                }
+
+                assert_eq!(
+                    find::reference_classify_quickly(&spacer, &extension, Some(&nicking), &final_guides, error_rate),
+                    find::reference_classify_quickly(&spacer, &extension, Some(&nicking), &all_final_guides, error_rate));
+                assert_eq!(
+                    find::reference_classify_carefully(&spacer, &extension, Some(&nicking), &efficient_guides, error_rate),
+                    find::reference_classify_carefully(&spacer, &extension, Some(&nicking), &all_efficient_guides, error_rate));
+            }
+        }
+    }
+
+    #[test]
+    fn exact_lookup_matches_myers() {
+        let error_rate = 0.25;
+        let mut bases = Bases(0x2545f4914f6cdd1d);
+        let library = library(&mut bases, 200, false);
+        let reference = Ref::from_tsv(&tsv(&library));
+        let final_guides = FinalGuides::new(&reference.guides, error_rate, true);
+        let (myers, _) = exhaustive(&reference.guides, error_rate);
+
+        let check = |spacer: &[u8], extension: &[u8], nicking: &[u8]| {
+            let found = final_guides.exact_matches(spacer, extension, Some(nicking), error_rate)?;
+            let searched = find::match_reference_all_quickly(spacer, extension, Some(nicking), &myers, error_rate);
+            assert_eq!(sorted(found.clone()), sorted(searched), "{} {} {}",
+                String::from_utf8_lossy(spacer), String::from_utf8_lossy(extension), String::from_utf8_lossy(nicking));
+            Some(found)
+        };
+
+        let mut looked_up = 0;
+        for _ in 0..300 {
+            let guide = &library[bases.below(library.len())];
+            let [spacer, extension, nicking] = guide.clone().map(|seq| mutate(&mut bases, &seq));
+            looked_up += usize::from(check(&spacer, &extension, &nicking).is_some());
+        }
+        // and reads one edit off their guide, which only the neighbours find
+        for _ in 0..300 {
+            let mut regions = library[bases.below(library.len())].clone();
+            let region = bases.below(3);
+            edit(&mut bases, &mut regions[region]);
+            let [spacer, extension, nicking] = &regions;
+            looked_up += usize::from(check(spacer, extension, nicking).is_some());
+        }
+        assert!(looked_up > 200);
+
+        // guides one substitution apart, read with a third base where they differ, are tied
+        let (a, b) = (&library[9], &library[10]);
+        let pos = (0..a[0].len()).find(|i| a[0][*i] != b[0][*i]).expect("Bad library!");
+        let mut spacer = a[0].clone();
+        spacer[pos] = *b"ACGT".iter().find(|base| **base != a[0][pos] && **base != b[0][pos]).expect("Bad base!");
+        let found = check(&spacer, &a[1], &a[2]).expect("Not looked up!");
+        assert_eq!(found.iter().map(|(name, _)| *name).sorted().collect_vec(), ["g10", "g9"]);
+
+        // reads losing the last base of a region are one deletion off
+        let [spacer, extension, nicking] = &library[3];
+        let found = check(&spacer[..spacer.len() - 1], extension, nicking).expect("Not looked up!");
+        assert_eq!(found, [("g3", Mismatch::new(spacer.len() + extension.len() + nicking.len(), 1))]);
+        check(spacer, extension, &nicking[1..]).expect("Not looked up!");
+    }
+
+    #[test]
+    fn exact_lookup_leaves_longer_guides_to_myers() {
+        let error_rate = 0.25;
+        let mut bases = Bases(0x853c49e6748fea9b);
+        let short = [bases.seq(20), bases.seq(10), bases.seq(10)];
+        let long = [bases.seq(20), bases.seq(50), bases.seq(20)];
+        let reference = Ref::from_tsv(&tsv(&[short.clone(), long.clone()]));
+        let final_guides = FinalGuides::new(&reference.guides, error_rate, true);
+
+        // a long guide with 2 edits would have a lower error rate than the short guide with 1
+        let mut spacer = short[0].clone();
+        spacer[5] = if spacer[5] == b'A' { b'C' } else { b'A' };
+        assert_eq!(final_guides.exact_matches(&spacer, &short[1], Some(&short[2]), error_rate), None);
+
+        // but not than the long guide with 1
+        let mut spacer = long[0].clone();
+        spacer[5] = if spacer[5] == b'A' { b'C' } else { b'A' };
+        assert_eq!(final_guides.exact_matches(&spacer, &long[1], Some(&long[2]), error_rate),
+            Some(vec![("g1", Mismatch::new(90, 1))]));
+    }
+}
@@ -0,0 +1,281 @@
+use std::{collections::HashMap, fmt::Write as _, io::Write};
+
+use itertools::Itertools;
+
+use crate::{coverage::Coverage, find::CLASSES, profile::ErrorProfile};
+
+/// Colours of the series in each plot, in order
+const COLOURS: [&str; 6] = ["#1b9e77", "#d95f02", "#7570b3", "#e7298a", "#66a61e", "#e6ab02"];
+
+const WIDTH: f64 = 720.0;
+const HEIGHT: f64 = 280.0;
+// space around the plotting area for the axes and their labels
+const LEFT: f64 = 70.0;
+const RIGHT: f64 = 20.0;
+const TOP: f64 = 20.0;
+const BOTTOM: f64 = 45.0;
+
+/// What the report is made from, all gathered during the run
+pub struct ReportData<'a> {
+    pub sample: Option<&'a str>,
+    // the number of reads in each class, in their order
+    pub classes: Vec<(&'static str, usize)>,
+    pub valid_counts: Vec<(String, u32)>,
+    pub partner_pairs: Vec<((&'a str, &'a str), u32)>,
+    pub error_profile: &'a ErrorProfile,
+    pub lengths: &'a HashMap<&'static str, HashMap<usize, u32>>,
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// Formats an axis label without needless decimals
+fn tick(value: f64) -> String {
+    if value.fract() == 0.0 && value.abs() < 1e9 {
+        format!("{}", value as i64)
+    } else {
+        format!("{:.3}", value)
+    }
+}
+
+struct Axes {
+    x_min: f64,
+    x_max: f64,
+    y_max: f64,
+}
+
+impl Axes {
+    fn x(&self, x: f64) -> f64 {
+        let range = if self.x_max > self.x_min { self.x_max - self.x_min } else { 1.0 };
+        LEFT + (x - self.x_min) / range * (WIDTH - LEFT - RIGHT)
+    }
+
+    fn y(&self, y: f64) -> f64 {
+        let range = if self.y_max > 0.0 { self.y_max } else { 1.0 };
+        HEIGHT - BOTTOM - y / range * (HEIGHT - TOP - BOTTOM)
+    }
+
+    /// Draws the axes with a few ticks, and their labels
+    fn draw(&self, svg: &mut String, xlab: &str, ylab: &str) {
+        let (x0, y0) = (LEFT, HEIGHT - BOTTOM);
+        write!(svg, r##"<line x1="{x0}" y1="{y0}" x2="{}" y2="{y0}" stroke="#333"/>"##, WIDTH - RIGHT).unwrap();
+        write!(svg, r##"<line x1="{x0}" y1="{y0}" x2="{x0}" y2="{TOP}" stroke="#333"/>"##).unwrap();
+
+        for i in 0..=4 {
+            let x = self.x_min + (self.x_max - self.x_min) * i as f64 / 4.0;
+            let y = self.y_max * i as f64 / 4.0;
+            write!(svg, r#"<text x="{}" y="{}" text-anchor="middle">{}</text>"#, self.x(x), y0 + 16.0, tick(x)).unwrap();
+            write!(svg, r#"<text x="{}" y="{}" text-anchor="end">{}</text>"#, x0 - 6.0, self.y(y) + 4.0, tick(y)).unwrap();
+        }
+
+        write!(svg, r#"<text x="{}" y="{}" text-anchor="middle">{}</text>"#, (LEFT + WIDTH - RIGHT) / 2.0, HEIGHT - 8.0, escape(xlab)).unwrap();
+        write!(svg, r#"<text x="14" y="{}" text-anchor="middle" transform="rotate(-90 14 {})">{}</text>"#,
+            (TOP + HEIGHT - BOTTOM) / 2.0, (TOP + HEIGHT - BOTTOM) / 2.0, escape(ylab)).unwrap();
+    }
+}
+
+fn svg_start() -> String {
+    format!(r#"<svg xmlns="http://www.w3.org/2000/svg" width="{WIDTH}" height="{HEIGHT}" viewBox="0 0 {WIDTH} {HEIGHT}">"#)
+}
+
+/// Draws each series as a line, with a legend when there's more than one
+fn line_chart(series: &[(String, Vec<(f64, f64)>)], xlab: &str, ylab: &str) -> String {
+    let points = series.iter().flat_map(|(_, points)| points).collect_vec();
+    let axes = Axes {
+        x_min: points.iter().map(|(x, _)| *x).fold(f64::INFINITY, f64::min).min(0.0),
+        x_max: points.iter().map(|(x, _)| *x).fold(0.0, f64::max),
+        y_max: points.iter().map(|(_, y)| *y).fold(0.0, f64::max),
+    };
+
+    let mut svg = svg_start();
+    axes.draw(&mut svg, xlab, ylab);
+    for (i, (name, points)) in series.iter().enumerate() {
+        let colour = COLOURS[i % COLOURS.len()];
+        let path = points.iter().map(|(x, y)| format!("{:.1},{:.1}", axes.x(*x), axes.y(*y))).join(" ");
+        write!(svg, r#"<polyline points="{}" fill="none" stroke="{}" stroke-width="1.5"/>"#, path, colour).unwrap();
+
+        if series.len() > 1 {
+            let y = TOP + 14.0 * i as f64;
+            write!(svg, r#"<rect x="{}" y="{}" width="10" height="10" fill="{}"/>"#, WIDTH - RIGHT - 150.0, y, colour).unwrap();
+            write!(svg, r#"<text x="{}" y="{}">{}</text>"#, WIDTH - RIGHT - 135.0, y + 9.0, escape(name)).unwrap();
+        }
+    }
+    svg.push_str("</svg>");
+    svg
+}
+
+/// Draws one bar for each bin, which start at the given values
+fn histogram(bins: &[(f64, f64)], width: f64, xlab: &str, ylab: &str) -> String {
+    let axes = Axes {
+        x_min: bins.first().map_or(0.0, |(x, _)| *x),
+        x_max: bins.last().map_or(1.0, |(x, _)| x + width),
+        y_max: bins.iter().map(|(_, y)| *y).fold(0.0, f64::max),
+    };
+
+    let mut svg = svg_start();
+    axes.draw(&mut svg, xlab, ylab);
+    for (x, y) in bins {
+        let (left, right) = (axes.x(*x), axes.x(x + width));
+        write!(svg, r#"<rect x="{:.1}" y="{:.1}" width="{:.1}" height="{:.1}" fill="{}"/>"#,
+            left, axes.y(*y), (right - left - 1.0).max(0.5), axes.y(0.0) - axes.y(*y), COLOURS[0]).unwrap();
+    }
+    svg.push_str("</svg>");
+    svg
+}
+
+/// Bins the valid reads of each guide, with one bin per count when there are few enough
+fn coverage_histogram(counts: &[u32]) -> String {
+    let max = counts.iter().copied().max().unwrap_or(0) as f64;
+    let width = if max < 50.0 { 1.0 } else { ((max + 1.0) / 40.0).ceil() };
+
+    let bins = counts.iter()
+        .map(|count| (*count as f64 / width).floor() as usize)
+        .counts();
+    let bins = (0..=(max / width) as usize)
+        .map(|bin| (bin as f64 * width, *bins.get(&bin).unwrap_or(&0) as f64))
+        .collect_vec();
+
+    histogram(&bins, width, "Valid reads per guide", "Guides")
+}
+
+/// The cumulative fraction of reads against the cumulative fraction of guides, from the least covered
+fn lorenz_curve(counts: &[u32]) -> String {
+    let total = counts.iter().map(|count| *count as f64).sum::<f64>();
+    let guides = counts.len() as f64;
+
+    let mut cumulative = 0.0;
+    let mut curve = vec![(0.0, 0.0)];
+    for (i, count) in counts.iter().sorted().enumerate() {
+        cumulative += *count as f64;
+        curve.push(((i + 1) as f64 / guides, if total > 0.0 { cumulative / total } else { 0.0 }));
+    }
+
+    line_chart(&[
+        (String::from("Library"), curve),
+        (String::from("Perfectly uniform"), vec![(0.0, 0.0), (1.0, 1.0)]),
+    ], "Fraction of guides", "Fraction of valid reads")
+}
+
+/// A table of the classes of reads, each with a bar of its fraction
+fn class_table(classes: &[(&'static str, usize)]) -> String {
+    let reads = classes.iter().map(|(_, count)| count).sum::<usize>();
+
+    let mut html = String::from("<table><tr><th>Class</th><th>Reads</th><th>Fraction</th><th></th></tr>");
+    for ((_, name), (_, count)) in CLASSES.iter().zip(classes) {
+        let fraction = *count as f64 / reads as f64;
+        write!(html, r#"<tr><td>{}</td><td>{}</td><td>{:.2}%</td><td><div class="bar" style="width: {:.1}px"></div></td></tr>"#,
+            name, count, fraction * 100.0, if fraction.is_finite() { fraction * 300.0 } else { 0.0 }).unwrap();
+    }
+    write!(html, "<tr><th>Total</th><th>{}</th><th></th><th></th></tr></table>", reads).unwrap();
+    html
+}
+
+fn partner_table(pairs: &[((&str, &str), u32)]) -> String {
+    if pairs.is_empty() {
+        return String::from("<p>No chimeric reads could be traced to their parent guides.</p>");
+    }
+
+    let mut html = String::from("<table><tr><th>Guide</th><th>Partner</th><th>Chimeric reads</th></tr>");
+    for ((a, b), reads) in pairs.iter().take(20) {
+        write!(html, "<tr><td>{}</td><td>{}</td><td>{}</td></tr>", escape(a), escape(b), reads).unwrap();
+    }
+    html.push_str("</table>");
+    html
+}
+
+/// Mismatch, insertion and deletion rates along each region
+fn error_plots(error_profile: &ErrorProfile) -> String {
+    let positions = error_profile.positions();
+    if positions.is_empty() {
+        return String::from("<p>No valid reads were profiled.</p>");
+    }
+
+    positions.into_iter()
+        .map(|(region, positions)| {
+            let rate = |f: fn(&crate::profile::PositionErrors) -> u32| positions.iter().enumerate()
+                .map(|(pos, e)| ((pos + 1) as f64, f(e) as f64 / (e.depth() as f64).max(1.0)))
+                .collect_vec();
+            format!("<h3>{}</h3>{}", region.name(), line_chart(&[
+                (String::from("Mismatches"), rate(|e| e.mismatches)),
+                (String::from("Insertions"), rate(|e| e.insertions)),
+                (String::from("Deletions"), rate(|e| e.deletions)),
+            ], "Position in region", "Rate"))
+        })
+        .join("\n")
+}
+
+fn length_plot(lengths: &HashMap<&'static str, HashMap<usize, u32>>) -> String {
+    let series = CLASSES.iter()
+        .filter_map(|(class, name)| {
+            let lengths = lengths.get(class)?;
+            let points = lengths.iter()
+                .sorted()
+                .map(|(length, reads)| (*length as f64, *reads as f64))
+                .collect_vec();
+            Some((name.to_string(), points))
+        })
+        .collect_vec();
+
+    line_chart(&series, "Read length", "Reads")
+}
+
+/// Writes a single self-contained HTML file, with every plot drawn inline as SVG
+pub fn write_report<T: Write>(output: &mut T, data: &ReportData) {
+    let title = match data.sample {
+        Some(sample) => format!("chimera report: {}", escape(sample)),
+        None => String::from("chimera report"),
+    };
+    let counts = data.valid_counts.iter().map(|(_, count)| *count).collect_vec();
+    let coverage = Coverage::new(&counts);
+
+    write!(output, r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{title}</title>
+<style>
+body {{ font-family: sans-serif; max-width: 760px; margin: 2em auto; color: #222; }}
+h2 {{ border-bottom: 1px solid #ccc; padding-bottom: 0.2em; margin-top: 2em; }}
+table {{ border-collapse: collapse; }}
+td, th {{ padding: 0.2em 0.8em; text-align: left; }}
+tr:nth-child(even) {{ background: #f4f4f4; }}
+.bar {{ height: 0.9em; background: {bar}; }}
+svg {{ font-size: 11px; }}
+</style>
+</head>
+<body>
+<h1>{title}</h1>
+<h2>Read classes</h2>
+{classes}
+<h2>Guide coverage</h2>
+<p>{guides} guides, of which {dropouts} have no valid reads. {within:.1}% are within 10x of the median of {median} reads per guide,
+the Gini index is {gini:.3}, and the 90th percentile has {skew:.1}x the reads of the 10th.</p>
+{histogram}
+<h3>Lorenz curve</h3>
+{lorenz}
+<h2>Top chimeric partners</h2>
+{partners}
+<h2>Error profiles of valid reads</h2>
+{errors}
+<h2>Read lengths by class</h2>
+{lengths}
+</body>
+</html>
+"#,
+        title = title,
+        bar = COLOURS[0],
+        classes = class_table(&data.classes),
+        guides = counts.len(),
+        dropouts = coverage.dropouts,
+        within = coverage.within_10x_median * 100.0,
+        median = coverage.reads_per_guide.median,
+        gini = coverage.gini,
+        skew = coverage.skew_ratio,
+        histogram = coverage_histogram(&counts),
+        lorenz = lorenz_curve(&counts),
+        partners = partner_table(&data.partner_pairs),
+        errors = error_plots(data.error_profile),
+        lengths = length_plot(data.lengths),
+    ).expect("Couldn't write HTML report!");
+}
@@ -0,0 +1,175 @@
+use std::{collections::{BTreeMap, HashMap}, fs, io::Write, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use itertools::Itertools;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::{cache::CacheSummary, coverage::Coverage, find, Args};
+
+#[derive(Serialize)]
+struct ClassSummary {
+    count: usize,
+    fraction: f64,
+}
+
+/// The stats of one sample, or of the whole run
+#[derive(Serialize)]
+struct StatsSummary {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sample: Option<String>,
+    reads: usize,
+    // the reads of each class, out of all of them
+    classes: BTreeMap<&'static str, ClassSummary>,
+    // counts which aren't classes, like the well-structured, escalated and deduplicated reads
+    stats: BTreeMap<&'static str, usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    coverage: Option<Coverage>,
+}
+
+impl StatsSummary {
+    /// Takes the stats `OutStats::stats` gives, including the number of reads, and the reads in each class
+    fn new(sample: Option<&str>, stats: &[(&'static str, usize)], classes: &[(&'static str, usize)], valid_counts: &[(String, u32)]) -> Self {
+        let reads = stats.iter()
+            .find(|(name, _)| *name == "reads")
+            .map_or(0, |(_, reads)| *reads);
+        let is_class = |name: &str| find::CLASSES.iter().any(|(class, _)| *class == name);
+
+        let classes: BTreeMap<_, _> = classes.iter()
+            .filter(|(name, _)| is_class(name))
+            .map(|(name, count)| (*name, ClassSummary { count: *count, fraction: *count as f64 / reads as f64 }))
+            .collect();
+        // the stats repeat some of the classes
+        let stats: BTreeMap<_, _> = stats.iter()
+            .filter(|(name, _)| *name != "reads" && !is_class(name))
+            .copied()
+            .collect();
+
+        let coverage = (!valid_counts.is_empty())
+            .then(|| Coverage::new(&valid_counts.iter().map(|(_, count)| *count).collect::<Vec<_>>()));
+
+        StatsSummary { sample: sample.map(String::from), reads, classes, stats, coverage }
+    }
+}
+
+#[derive(Serialize)]
+struct ReferenceSummary {
+    path: String,
+    sha256: String,
+    guides: usize,
+}
+
+#[derive(Serialize)]
+struct Timings {
+    started: u64,
+    reference_seconds: f64,
+    classification_seconds: f64,
+    total_seconds: f64,
+    reads_per_second: f64,
+}
+
+/// Everything about a run, gathered as it goes and written as JSON at the end
+#[derive(Serialize)]
+pub struct Summary<'a> {
+    tool: &'static str,
+    version: &'static str,
+    parameters: &'a Args,
+    references: Vec<ReferenceSummary>,
+    timings: Timings,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cache: Option<CacheSummary>,
+    // worked out from the summed stats and valid reads once they're all in
+    #[serde(flatten)]
+    totals: StatsSummary,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    samples: Vec<StatsSummary>,
+    // stats, classes and valid reads of each guide, summed over the samples
+    #[serde(skip)]
+    stats: HashMap<&'static str, usize>,
+    #[serde(skip)]
+    classes: HashMap<&'static str, usize>,
+    #[serde(skip)]
+    valid_counts: HashMap<String, u32>,
+}
+
+impl<'a> Summary<'a> {
+    pub fn new(args: &'a Args) -> Self {
+        Summary {
+            tool: env!("CARGO_PKG_NAME"),
+            version: env!("CARGO_PKG_VERSION"),
+            parameters: args,
+            references: Vec::new(),
+            timings: Timings {
+                started: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |t| t.as_secs()),
+                reference_seconds: 0.0,
+                classification_seconds: 0.0,
+                total_seconds: 0.0,
+                reads_per_second: 0.0,
+            },
+            cache: None,
+            totals: StatsSummary::new(None, &[], &[], &[]),
+            samples: Vec::new(),
+            stats: HashMap::new(),
+            classes: HashMap::new(),
+            valid_counts: HashMap::new(),
+        }
+    }
+
+    /// Records a reference, and how long it took to build
+    pub fn add_reference(&mut self, path: &str, guides: usize, elapsed: Duration) {
+        let contents = fs::read(path).expect("Couldn't read reference!");
+        let sha256 = Sha256::digest(&contents).iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+
+        self.references.push(ReferenceSummary { path: path.to_string(), sha256, guides });
+        self.timings.reference_seconds += elapsed.as_secs_f64();
+    }
+
+    pub fn add_classification(&mut self, elapsed: Duration) {
+        self.timings.classification_seconds += elapsed.as_secs_f64();
+    }
+
+    /// Adds how well the cache of one reference did
+    pub fn add_cache(&mut self, cache: CacheSummary) {
+        self.cache.get_or_insert_with(CacheSummary::default).add(cache);
+    }
+
+    /// Adds the stats of one sample. A run with several samples lists each, as well as their totals
+    pub fn add_sample(
+        &mut self,
+        sample: Option<&str>,
+        stats: &[(&'static str, usize)],
+        classes: &[(&'static str, usize)],
+        valid_counts: &[(String, u32)]
+    ) {
+        if sample.is_some() {
+            self.samples.push(StatsSummary::new(sample, stats, classes, valid_counts));
+        }
+
+        for (guide, count) in valid_counts {
+            *self.valid_counts.entry(guide.clone()).or_default() += count;
+        }
+        for (name, value) in stats {
+            *self.stats.entry(name).or_default() += value;
+        }
+        for (class, count) in classes {
+            *self.classes.entry(class).or_default() += count;
+        }
+    }
+
+    /// Finishes the timings, and writes the summary
+    pub fn write<T: Write>(mut self, output: &mut T, total: Duration) {
+        let stats = self.stats.iter().map(|(name, value)| (*name, *value)).collect_vec();
+        let classes = self.classes.iter().map(|(class, count)| (*class, *count)).collect_vec();
+        let valid_counts = self.valid_counts.iter().map(|(guide, count)| (guide.clone(), *count)).collect_vec();
+        self.totals = StatsSummary::new(None, &stats, &classes, &valid_counts);
+
+        self.timings.total_seconds = total.as_secs_f64();
+        self.timings.reads_per_second = self.totals.reads as f64 / self.timings.classification_seconds;
+
+        serde_json::to_writer_pretty(&mut *output, &self)
+            .expect("Couldn't write summary!");
+        writeln!(output)
+            .expect("Couldn't write summary!");
+    }
+}
@@ -0,0 +1,26 @@
+use itertools::Itertools;
+
+/// Random bases from a fixed seed, so tests and benchmarks see the same sequences every run
+pub struct Bases(pub u64);
+
+impl Bases {
+    // xorshift64
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    pub fn base(&mut self) -> u8 {
+        b"ACGT"[self.below(4)]
+    }
+
+    pub fn seq(&mut self, len: usize) -> Vec<u8> {
+        (0..len).map(|_| self.base()).collect_vec()
+    }
+}
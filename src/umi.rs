@@ -0,0 +1,231 @@
+use std::{collections::{HashMap, HashSet}, io::Write};
+
+use clap::ValueEnum;
+use itertools::Itertools;
+
+use crate::{Args, find::{ChimeraParents, Regions}, reference::{Ref, TracedPattern}};
+
+/// Where the random region sits in the cassette
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UmiLocation {
+    // between the second cys4 and the nicking guide
+    BeforeNicking,
+    // after the last scaffold
+    AfterNicking,
+}
+
+/// A named random region, extracted from each read rather than matched against the reference
+pub struct Umi {
+    pub name: String,
+    location: UmiLocation,
+    min_len: usize,
+    max_len: usize,
+    // sequence directly after the UMI, which bounds it when its length varies
+    flank: Option<TracedPattern>,
+}
+
+impl Umi {
+    pub fn new(args: &Args) -> Option<Umi> {
+        let length = args.umi_length.as_ref()?;
+        let (min_len, max_len) = match length.split_once('-') {
+            Some((min, max)) => (
+                min.parse().expect("Bad UMI length!"),
+                max.parse().expect("Bad UMI length!")),
+            None => {
+                let len = length.parse().expect("Bad UMI length!");
+                (len, len)
+            },
+        };
+
+        if min_len > max_len || max_len == 0 {
+            panic!("Bad UMI length!");
+        }
+        if min_len != max_len && args.umi_flank.is_none() {
+            panic!("A UMI of bounded length needs --umi-flank!");
+        }
+
+        Some(Umi {
+            name: args.umi_name.clone(),
+            location: args.umi_location,
+            min_len,
+            max_len,
+            flank: args.umi_flank.as_ref().map(|flank| TracedPattern::new(flank.as_bytes())),
+        })
+    }
+
+    /// Takes the UMI from the start of its region, or None if the read is too short or the flank is missing
+    pub fn extract<'a>(&self, regions: &Regions<'a>, error_rate: f32) -> Option<&'a [u8]> {
+        let region = match self.location {
+            // reads without a nicking guide have their second segment in the tail
+            UmiLocation::BeforeNicking => regions.nicking.unwrap_or(regions.tail),
+            UmiLocation::AfterNicking => regions.tail,
+        };
+
+        match &self.flank {
+            Some(flank) => {
+                let end = flank.get_matches(region, error_rate).iter()
+                    .map(|(start, _, _)| *start)
+                    .filter(|start| (self.min_len..=self.max_len).contains(start))
+                    .min()?;
+                Some(&region[..end])
+            },
+            None if region.len() >= self.max_len => Some(&region[..self.max_len]),
+            None => None,
+        }
+    }
+}
+
+/// Distinct UMIs seen for each guide
+#[derive(Default)]
+pub struct UmiCounts {
+    umis: HashMap<String, HashSet<Vec<u8>>>,
+    // reads with no UMI to take, which can't be deduplicated
+    missing: usize,
+}
+
+impl UmiCounts {
+    pub fn add(&mut self, guide: &str, umi: Option<&[u8]>) {
+        let Some(umi) = umi else {
+            self.missing += 1;
+            return;
+        };
+        match self.umis.get_mut(guide) {
+            Some(umis) => { umis.insert(umi.to_vec()); },
+            None => { self.umis.insert(guide.to_string(), HashSet::from([umi.to_vec()])); },
+        }
+    }
+
+    pub fn get(&self, guide: &str) -> usize {
+        self.umis.get(guide).map_or(0, |umis| umis.len())
+    }
+
+    pub fn contains(&self, guide: &str, umi: &[u8]) -> bool {
+        self.umis.get(guide).is_some_and(|umis| umis.contains(umi))
+    }
+
+    /// Number of distinct (UMI, guide) molecules
+    pub fn total(&self) -> usize {
+        self.umis.values().map(|umis| umis.len()).sum()
+    }
+
+    /// Number of reads left out of the molecules, as they had no UMI
+    pub fn missing(&self) -> usize {
+        self.missing
+    }
+}
+
+/// Reads and distinct UMIs of the chimeras with one signature
+struct ChimeraMolecules {
+    reads: u32,
+    umis: HashSet<Vec<u8>>,
+}
+
+/// Chimeric reads, collapsed by their parent guides and UMI
+#[derive(Default)]
+pub struct ChimeraCounts {
+    signatures: HashMap<ChimeraParents, ChimeraMolecules>,
+    // reads with no UMI to take, which can't be deduplicated
+    missing: usize,
+}
+
+impl ChimeraCounts {
+    pub fn add(&mut self, parents: ChimeraParents, umi: Option<&[u8]>) {
+        let molecules = self.signatures.entry(parents)
+            .or_insert_with(|| ChimeraMolecules { reads: 0, umis: HashSet::new() });
+
+        molecules.reads += 1;
+        match umi {
+            Some(umi) => { molecules.umis.insert(umi.to_vec()); },
+            None => self.missing += 1,
+        }
+    }
+
+    /// Number of distinct (UMI, signature) molecules
+    pub fn deduplicated(&self) -> usize {
+        self.signatures.values().map(|molecules| molecules.umis.len()).sum()
+    }
+
+    /// Number of reads left out of the molecules, as they had no UMI
+    pub fn missing(&self) -> usize {
+        self.missing
+    }
+
+    /// Chimeric molecules whose UMI is shared with a valid read of one of their parent guides. 
+    /// These most likely switched template during PCR, rather than being cloned into the pool
+    fn pcr(parents: &ChimeraParents, molecules: &ChimeraMolecules, guide_umis: &UmiCounts) -> usize {
+        let guides = parents.guides();
+        molecules.umis.iter()
+            .filter(|umi| guides.iter().any(|guide| guide_umis.contains(guide, umi)))
+            .count()
+    }
+
+    pub fn pcr_total(&self, guide_umis: &UmiCounts) -> usize {
+        self.signatures.iter().map(|(parents, molecules)| ChimeraCounts::pcr(parents, molecules, guide_umis)).sum()
+    }
+
+    /// Chimeric reads for each pair of guides which contributed regions to the same read, most frequent first
+    pub fn partner_pairs(&self) -> Vec<((&str, &str), u32)> {
+        let mut pairs: HashMap<(&str, &str), u32> = HashMap::new();
+        for (parents, molecules) in &self.signatures {
+            for [a, b] in parents.guides().into_iter().sorted().array_combinations() {
+                *pairs.entry((a, b)).or_default() += molecules.reads;
+            }
+        }
+
+        pairs.into_iter()
+            .sorted_by(|(p1, r1), (p2, r2)| r2.cmp(r1).then(p1.cmp(p2)))
+            .collect_vec()
+    }
+
+    /// The parent guides and number of reads of each chimera signature
+    pub fn parents(&self) -> impl Iterator<Item = (&ChimeraParents, u32)> {
+        self.signatures.iter().map(|(parents, molecules)| (parents, molecules.reads))
+    }
+
+    /// Writes the chimeric reads of each pair of partner guides, and with pools, whether they share one
+    pub fn write_partners<T: Write>(&self, output: &mut T, reference: &Ref) {
+        let pooled = !reference.pools.is_empty();
+        let mut header = vec!["guide_a", "guide_b", "reads"];
+        if pooled {
+            header.extend(["pool_a", "pool_b", "pools"]);
+        }
+        writeln!(output, "{}", header.join("\t"))
+            .expect("Couldn't write header line to chimera partners!");
+
+        for ((a, b), reads) in self.partner_pairs() {
+            let mut row = vec![a.to_string(), b.to_string(), reads.to_string()];
+            if pooled {
+                let scope = if reference.same_pool(&[a, b]) { "intra" } else { "inter" };
+                row.extend([reference.pool(a).to_string(), reference.pool(b).to_string(), scope.to_string()]);
+            }
+            writeln!(output, "{}", row.join("\t"))
+                .expect("Couldn't write line to chimera partners!");
+        }
+    }
+
+    /// Writes one row per chimera signature, most frequent first
+    pub fn write<T: Write>(&self, output: &mut T, guide_umis: &UmiCounts, dedup: bool) {
+        let mut header = vec!["signature", "spacer", "extension", "nicking", "reads"];
+        if dedup {
+            header.extend(["deduplicated", "pcr"]);
+        }
+        writeln!(output, "{}", header.join("\t"))
+            .expect("Couldn't write header line to chimera counts!");
+
+        let sorted = self.signatures.iter()
+            .map(|(parents, molecules)| (parents.signature(), parents, molecules))
+            .sorted_by(|(s1, _, m1), (s2, _, m2)| m2.reads.cmp(&m1.reads).then(s1.cmp(s2)));
+        for (signature, parents, molecules) in sorted {
+            let mut row = vec![signature];
+            row.extend(parents.regions());
+            row.push(molecules.reads.to_string());
+            if dedup {
+                row.push(molecules.umis.len().to_string());
+                row.push(ChimeraCounts::pcr(parents, molecules, guide_umis).to_string());
+            }
+            writeln!(output, "{}", row.join("\t"))
+                .expect("Couldn't write line to chimera counts!");
+        }
+    }
+}